@@ -1,98 +1,1258 @@
-use crate::expr::LiteralValue;
-use std::{cell::RefCell, collections::HashMap, rc::Rc};
+use crate::expr::{next_callable_id, AritySpec, LiteralValue};
+use crate::output;
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    error::Error,
+    rc::{Rc, Weak},
+};
 
 // The Environment holds all the variables and their values if any and also holds a reference to a
 // parent Environment if any
+// Runs the initializer of a lazy var
+pub type Thunk = Rc<dyn Fn() -> Result<LiteralValue, Box<dyn Error>>>;
+
 pub struct Environment {
     values: HashMap<String, LiteralValue>,
     // Enclosing is the parent Environment to the current Environment
     pub enclosing: Option<Rc<RefCell<Environment>>>,
     globals: HashMap<String, LiteralValue>,
+    // Types of the values and globals declared with one
+    types: HashMap<String, String>,
+    global_types: HashMap<String, String>,
+    // Initializers of lazy vars that have not been read yet
+    deferred: HashMap<String, Thunk>,
+    global_deferred: HashMap<String, Thunk>,
 }
 
-#[allow(clippy::ptr_arg)]
-fn clock_impl(_args: &Vec<LiteralValue>) -> LiteralValue {
+// Seconds since the epoch with millisecond precision, what clock gives
+fn now_seconds() -> f64 {
     let now = std::time::SystemTime::now()
         .duration_since(std::time::SystemTime::UNIX_EPOCH)
         .expect("Could not get system time")
         .as_millis();
-    LiteralValue::Number(now as f64 / 1000.0)
+    now as f64 / 1000.0
 }
 
-fn get_globals() -> HashMap<String, LiteralValue> {
-    let mut env = HashMap::new();
+#[allow(clippy::ptr_arg)]
+fn clock_impl(_args: &Vec<LiteralValue>) -> Result<LiteralValue, Box<dyn Error>> {
+    Ok(LiteralValue::Number(now_seconds()))
+}
+
+// Call a callable with no arguments, print how long it took and give back its result
+#[allow(clippy::ptr_arg)]
+fn time_it_impl(args: &Vec<LiteralValue>) -> Result<LiteralValue, Box<dyn Error>> {
+    match &args[0] {
+        LiteralValue::Callable {
+            id: _,
+            name,
+            arity,
+            line: _,
+            fun,
+        } => {
+            if !arity.accepts(0) {
+                return Err(format!(
+                    "time_it expected a Callable taking 0 arguments and '{}' takes {}",
+                    name, arity
+                )
+                .into());
+            }
+            let start = now_seconds();
+            let result = fun(&vec![])?;
+            let elapsed = (now_seconds() - start) * 1000.0;
+            output::write_line(&format!("{} took {}ms", name, elapsed.round()))?;
+            Ok(result)
+        }
+        e => Err(format!("time_it expected Callable and got {}", e.to_type()).into()),
+    }
+}
+
+// Return the single char string at the given index
+#[allow(clippy::ptr_arg)]
+fn char_at_impl(args: &Vec<LiteralValue>) -> Result<LiteralValue, Box<dyn Error>> {
+    match (&args[0], &args[1]) {
+        (LiteralValue::StringValue(s), LiteralValue::Number(i)) => {
+            if *i < 0.0 || i.fract() != 0.0 {
+                return Err(format!("char_at index must be a non-negative integer, got {}", i).into());
+            }
+            match s.chars().nth(*i as usize) {
+                Some(c) => Ok(LiteralValue::StringValue(Rc::from(c.to_string()))),
+                None => Err(format!(
+                    "char_at index {} out of range for string of length {}",
+                    i,
+                    s.chars().count()
+                )
+                .into()),
+            }
+        }
+        (s, i) => Err(format!(
+            "char_at expected (String, Number) and got ({}, {})",
+            s.to_type(),
+            i.to_type()
+        )
+        .into()),
+    }
+}
+
+// Number of chars in a string
+#[allow(clippy::ptr_arg)]
+fn len_impl(args: &Vec<LiteralValue>) -> Result<LiteralValue, Box<dyn Error>> {
+    match &args[0] {
+        LiteralValue::StringValue(s) => Ok(LiteralValue::Number(s.chars().count() as f64)),
+        e => Err(format!("len expected String and got {}", e.to_type()).into()),
+    }
+}
+
+// The chars of a string from start up to but not including end
+#[allow(clippy::ptr_arg)]
+fn substr_impl(args: &Vec<LiteralValue>) -> Result<LiteralValue, Box<dyn Error>> {
+    match (&args[0], &args[1], &args[2]) {
+        (LiteralValue::StringValue(s), LiteralValue::Number(start), LiteralValue::Number(end)) => {
+            let len = s.chars().count();
+            if start.fract() != 0.0 || end.fract() != 0.0 {
+                return Err(format!(
+                    "substr indices must be integers, got {} and {}",
+                    start, end
+                )
+                .into());
+            }
+            if *start < 0.0 || start > end || *end > len as f64 {
+                return Err(format!(
+                    "substr range {}..{} out of range for string of length {}",
+                    start, end, len
+                )
+                .into());
+            }
+            let sub: String = s
+                .chars()
+                .skip(*start as usize)
+                .take((end - start) as usize)
+                .collect();
+            Ok(LiteralValue::StringValue(Rc::from(sub)))
+        }
+        (s, start, end) => Err(format!(
+            "substr expected (String, Number, Number) and got ({}, {}, {})",
+            s.to_type(),
+            start.to_type(),
+            end.to_type()
+        )
+        .into()),
+    }
+}
+
+#[allow(clippy::ptr_arg)]
+fn upper_impl(args: &Vec<LiteralValue>) -> Result<LiteralValue, Box<dyn Error>> {
+    match &args[0] {
+        LiteralValue::StringValue(s) => Ok(LiteralValue::StringValue(Rc::from(s.to_uppercase()))),
+        e => Err(format!("upper expected String and got {}", e.to_type()).into()),
+    }
+}
+
+#[allow(clippy::ptr_arg)]
+fn lower_impl(args: &Vec<LiteralValue>) -> Result<LiteralValue, Box<dyn Error>> {
+    match &args[0] {
+        LiteralValue::StringValue(s) => Ok(LiteralValue::StringValue(Rc::from(s.to_lowercase()))),
+        e => Err(format!("lower expected String and got {}", e.to_type()).into()),
+    }
+}
+
+// Parse a string into a Number, surrounding whitespace is ignored
+#[allow(clippy::ptr_arg)]
+fn to_number_impl(args: &Vec<LiteralValue>) -> Result<LiteralValue, Box<dyn Error>> {
+    match &args[0] {
+        LiteralValue::StringValue(s) => match s.trim().parse::<f64>() {
+            Ok(n) if n.is_finite() => Ok(LiteralValue::Number(n)),
+            _ => Err(format!("to_number could not parse '{}' as a Number", s).into()),
+        },
+        e => Err(format!("to_number expected String and got {}", e.to_type()).into()),
+    }
+}
+
+// Any value as the string print would show for it
+#[allow(clippy::ptr_arg)]
+fn to_string_impl(args: &Vec<LiteralValue>) -> Result<LiteralValue, Box<dyn Error>> {
+    Ok(LiteralValue::StringValue(Rc::from(args[0].display_string())))
+}
+
+// Return the code point of the first char of a string
+#[allow(clippy::ptr_arg)]
+fn char_code_impl(args: &Vec<LiteralValue>) -> Result<LiteralValue, Box<dyn Error>> {
+    match &args[0] {
+        LiteralValue::StringValue(s) => match s.chars().next() {
+            Some(c) => Ok(LiteralValue::Number(c as u32 as f64)),
+            None => Err("char_code called on an empty string".into()),
+        },
+        e => Err(format!("char_code expected String and got {}", e.to_type()).into()),
+    }
+}
+
+// Build a single char string from a code point
+#[allow(clippy::ptr_arg)]
+fn from_char_code_impl(args: &Vec<LiteralValue>) -> Result<LiteralValue, Box<dyn Error>> {
+    match &args[0] {
+        LiteralValue::Number(n) => {
+            if *n < 0.0 || n.fract() != 0.0 || *n > u32::MAX as f64 {
+                return Err(format!("{} is not a valid char code", n).into());
+            }
+            match char::from_u32(*n as u32) {
+                Some(c) => Ok(LiteralValue::StringValue(Rc::from(c.to_string()))),
+                None => Err(format!("{} is not a valid char code", n).into()),
+            }
+        }
+        e => Err(format!("from_char_code expected Number and got {}", e.to_type()).into()),
+    }
+}
+
+// Format the integer part of a number with ',' between every group of three digits
+#[allow(clippy::ptr_arg)]
+fn group_impl(args: &Vec<LiteralValue>) -> Result<LiteralValue, Box<dyn Error>> {
+    match &args[0] {
+        LiteralValue::Number(n) => {
+            let digits = n.abs().to_string();
+            let (int_part, frac_part) = match digits.split_once('.') {
+                Some((int_part, frac_part)) => (int_part.to_string(), Some(frac_part.to_string())),
+                None => (digits, None),
+            };
+
+            let mut grouped = String::new();
+            for (i, c) in int_part.chars().enumerate() {
+                if i > 0 && (int_part.len() - i) % 3 == 0 {
+                    grouped.push(',');
+                }
+                grouped.push(c);
+            }
+            if *n < 0.0 {
+                grouped.insert(0, '-');
+            }
+            if let Some(frac_part) = frac_part {
+                grouped.push('.');
+                grouped.push_str(&frac_part);
+            }
+            Ok(LiteralValue::StringValue(Rc::from(grouped)))
+        }
+        e => Err(format!("group expected Number and got {}", e.to_type()).into()),
+    }
+}
+
+// Bound a number to the range [lo, hi]
+#[allow(clippy::ptr_arg)]
+fn clamp_impl(args: &Vec<LiteralValue>) -> Result<LiteralValue, Box<dyn Error>> {
+    match (&args[0], &args[1], &args[2]) {
+        (LiteralValue::Number(x), LiteralValue::Number(lo), LiteralValue::Number(hi)) => {
+            if lo > hi {
+                return Err(format!(
+                    "clamp lower bound {} is greater than upper bound {}",
+                    lo, hi
+                )
+                .into());
+            }
+            Ok(LiteralValue::Number(x.clamp(*lo, *hi)))
+        }
+        (x, lo, hi) => Err(format!(
+            "clamp expected (Number, Number, Number) and got ({}, {}, {})",
+            x.to_type(),
+            lo.to_type(),
+            hi.to_type()
+        )
+        .into()),
+    }
+}
+
+// Linear interpolation between a and b by t
+#[allow(clippy::ptr_arg)]
+fn lerp_impl(args: &Vec<LiteralValue>) -> Result<LiteralValue, Box<dyn Error>> {
+    match (&args[0], &args[1], &args[2]) {
+        (LiteralValue::Number(a), LiteralValue::Number(b), LiteralValue::Number(t)) => {
+            Ok(LiteralValue::Number(a + (b - a) * t))
+        }
+        (a, b, t) => Err(format!(
+            "lerp expected (Number, Number, Number) and got ({}, {}, {})",
+            a.to_type(),
+            b.to_type(),
+            t.to_type()
+        )
+        .into()),
+    }
+}
+
+// |a - b|
+#[allow(clippy::ptr_arg)]
+fn abs_diff_impl(args: &Vec<LiteralValue>) -> Result<LiteralValue, Box<dyn Error>> {
+    match (&args[0], &args[1]) {
+        (LiteralValue::Number(a), LiteralValue::Number(b)) => {
+            Ok(LiteralValue::Number((a - b).abs()))
+        }
+        (a, b) => Err(format!(
+            "abs_diff expected (Number, Number) and got ({}, {})",
+            a.to_type(),
+            b.to_type()
+        )
+        .into()),
+    }
+}
+
+// -1, 0 or 1, -0 counts as 0 and NaN stays NaN
+#[allow(clippy::ptr_arg)]
+fn sign_impl(args: &Vec<LiteralValue>) -> Result<LiteralValue, Box<dyn Error>> {
+    match &args[0] {
+        LiteralValue::Number(x) if x.is_nan() => Ok(LiteralValue::Number(*x)),
+        LiteralValue::Number(x) if *x > 0.0 => Ok(LiteralValue::Number(1.0)),
+        LiteralValue::Number(x) if *x < 0.0 => Ok(LiteralValue::Number(-1.0)),
+        LiteralValue::Number(_) => Ok(LiteralValue::Number(0.0)),
+        x => Err(format!("sign expected Number and got {}", x.to_type()).into()),
+    }
+}
+
+// Give back value if its type is type_name, the same names ':' annotations use, else error
+#[allow(clippy::ptr_arg)]
+fn check_type_impl(args: &Vec<LiteralValue>) -> Result<LiteralValue, Box<dyn Error>> {
+    match &args[1] {
+        LiteralValue::StringValue(type_name) => {
+            if args[0].to_type() == type_name.as_ref() {
+                Ok(args[0].clone())
+            } else {
+                Err(format!(
+                    "check_type expected {} and got {}",
+                    type_name,
+                    args[0].to_type()
+                )
+                .into())
+            }
+        }
+        e => Err(format!("check_type expected a String type name and got {}", e.to_type()).into()),
+    }
+}
+
+// Used by assert_approx when no epsilon is given and always by '~='
+pub const DEFAULT_EPSILON: f64 = 1e-9;
+
+// Error unless a and b are within epsilon of each other
+#[allow(clippy::ptr_arg)]
+fn assert_approx_impl(args: &Vec<LiteralValue>) -> Result<LiteralValue, Box<dyn Error>> {
+    let epsilon = match args.get(2) {
+        Some(LiteralValue::Number(e)) => *e,
+        Some(e) => {
+            return Err(
+                format!("assert_approx expected Number epsilon and got {}", e.to_type()).into(),
+            )
+        }
+        None => DEFAULT_EPSILON,
+    };
+    match (&args[0], &args[1]) {
+        (LiteralValue::Number(a), LiteralValue::Number(b)) => {
+            let diff = (a - b).abs();
+            if diff <= epsilon {
+                Ok(LiteralValue::Nil)
+            } else {
+                Err(format!(
+                    "assert_approx failed: {} and {} differ by {} which is more than {}",
+                    a, b, diff, epsilon
+                )
+                .into())
+            }
+        }
+        (a, b) => Err(format!(
+            "assert_approx expected (Number, Number) and got ({}, {})",
+            a.to_type(),
+            b.to_type()
+        )
+        .into()),
+    }
+}
+
+// Largest integer a f64 holds without losing precision
+const MAX_SAFE_INTEGER: f64 = 9007199254740991.0;
+
+// Check that both args are integral numbers and return their absolute values
+fn integer_pair(name: &str, args: &[LiteralValue]) -> Result<(u64, u64), Box<dyn Error>> {
+    match (&args[0], &args[1]) {
+        (LiteralValue::Number(a), LiteralValue::Number(b)) => {
+            for n in [a, b] {
+                if n.fract() != 0.0 || n.abs() > MAX_SAFE_INTEGER {
+                    return Err(format!("{} expected integers and got {}", name, n).into());
+                }
+            }
+            Ok((a.abs() as u64, b.abs() as u64))
+        }
+        (a, b) => Err(format!(
+            "{} expected (Number, Number) and got ({}, {})",
+            name,
+            a.to_type(),
+            b.to_type()
+        )
+        .into()),
+    }
+}
+
+// Euclidean algorithm
+fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+// Greatest common divisor of two integers
+#[allow(clippy::ptr_arg)]
+fn gcd_impl(args: &Vec<LiteralValue>) -> Result<LiteralValue, Box<dyn Error>> {
+    let (a, b) = integer_pair("gcd", args)?;
+    Ok(LiteralValue::Number(gcd(a, b) as f64))
+}
+
+// Least common multiple of two integers, errors if it is too big to be exact
+#[allow(clippy::ptr_arg)]
+fn lcm_impl(args: &Vec<LiteralValue>) -> Result<LiteralValue, Box<dyn Error>> {
+    let (a, b) = integer_pair("lcm", args)?;
+    if a == 0 || b == 0 {
+        return Ok(LiteralValue::Number(0.0));
+    }
+    match (a / gcd(a, b)).checked_mul(b) {
+        Some(lcm) if lcm as f64 <= MAX_SAFE_INTEGER => Ok(LiteralValue::Number(lcm as f64)),
+        _ => Err(format!("lcm of {} and {} is too large", a, b).into()),
+    }
+}
+
+// Check the (a, b, modulus) arguments of the wrap natives
+fn wrap_args(name: &str, args: &[LiteralValue]) -> Result<(i64, i64, i64), Box<dyn Error>> {
+    match (&args[0], &args[1], &args[2]) {
+        (LiteralValue::Number(a), LiteralValue::Number(b), LiteralValue::Number(m)) => {
+            for n in [a, b, m] {
+                if n.fract() != 0.0 || n.abs() > MAX_SAFE_INTEGER {
+                    return Err(format!("{} expected integers and got {}", name, n).into());
+                }
+            }
+            if *m <= 0.0 {
+                return Err(format!("{} expected a positive modulus and got {}", name, m).into());
+            }
+            Ok((*a as i64, *b as i64, *m as i64))
+        }
+        (a, b, m) => Err(format!(
+            "{} expected (Number, Number, Number) and got ({}, {}, {})",
+            name,
+            a.to_type(),
+            b.to_type(),
+            m.to_type()
+        )
+        .into()),
+    }
+}
+
+// (a + b) mod modulus, always in 0..modulus
+#[allow(clippy::ptr_arg)]
+fn wrap_add_impl(args: &Vec<LiteralValue>) -> Result<LiteralValue, Box<dyn Error>> {
+    let (a, b, m) = wrap_args("wrap_add", args)?;
+    Ok(LiteralValue::Number((a + b).rem_euclid(m) as f64))
+}
+
+// (a - b) mod modulus, always in 0..modulus
+#[allow(clippy::ptr_arg)]
+fn wrap_sub_impl(args: &Vec<LiteralValue>) -> Result<LiteralValue, Box<dyn Error>> {
+    let (a, b, m) = wrap_args("wrap_sub", args)?;
+    Ok(LiteralValue::Number((a - b).rem_euclid(m) as f64))
+}
+
+// Check the radix argument of the base natives
+fn radix_arg(name: &str, value: &LiteralValue) -> Result<u32, Box<dyn Error>> {
+    match value {
+        LiteralValue::Number(r) if r.fract() == 0.0 && (2.0..=36.0).contains(r) => Ok(*r as u32),
+        LiteralValue::Number(r) => {
+            Err(format!("{} expected a radix from 2 to 36 and got {}", name, r).into())
+        }
+        other => {
+            Err(format!("{} expected a Number radix and got {}", name, other.to_type()).into())
+        }
+    }
+}
+
+// Digits of an integer in the given radix, lowercase letters past 9
+#[allow(clippy::ptr_arg)]
+fn to_base_impl(args: &Vec<LiteralValue>) -> Result<LiteralValue, Box<dyn Error>> {
+    let radix = radix_arg("to_base", &args[1])?;
+    let n = match &args[0] {
+        LiteralValue::Number(n) if n.fract() == 0.0 && n.abs() <= MAX_SAFE_INTEGER => *n,
+        LiteralValue::Number(n) => {
+            return Err(format!(
+                "to_base expected an integer and got {}",
+                LiteralValue::format_number(*n)
+            )
+            .into())
+        }
+        other => {
+            return Err(format!("to_base expected a Number and got {}", other.to_type()).into())
+        }
+    };
+    let mut rest = n.abs() as u64;
+    let mut digits = vec![];
+    loop {
+        digits.push(std::char::from_digit((rest % radix as u64) as u32, radix).unwrap());
+        rest /= radix as u64;
+        if rest == 0 {
+            break;
+        }
+    }
+    if n < 0.0 {
+        digits.push('-');
+    }
+    let digits: String = digits.into_iter().rev().collect();
+    Ok(LiteralValue::StringValue(Rc::from(digits)))
+}
+
+// Parse the digits of an integer in the given radix, the reverse of to_base
+#[allow(clippy::ptr_arg)]
+fn from_base_impl(args: &Vec<LiteralValue>) -> Result<LiteralValue, Box<dyn Error>> {
+    let radix = radix_arg("from_base", &args[1])?;
+    match &args[0] {
+        LiteralValue::StringValue(s) => match i64::from_str_radix(s, radix) {
+            Ok(n) if n.unsigned_abs() as f64 <= MAX_SAFE_INTEGER => {
+                Ok(LiteralValue::Number(n as f64))
+            }
+            Ok(_) => Err(format!("from_base result of '{}' is too large", s).into()),
+            Err(_) => {
+                Err(format!("from_base got invalid digits '{}' for base {}", s, radix).into())
+            }
+        },
+        other => Err(format!("from_base expected a String and got {}", other.to_type()).into()),
+    }
+}
+
+// Hashable form of a LiteralValue used as a memoize cache key
+#[derive(PartialEq, Eq, Hash)]
+enum MemoKey {
+    Number(u64),
+    StringValue(Rc<str>),
+    Bool(bool),
+    Nil,
+    Callable(usize),
+}
+
+// None for classes and instances, their fields can change so a cached result could go stale
+fn memo_key(value: &LiteralValue) -> Option<MemoKey> {
+    match value {
+        // -0 and 0 are the same argument
+        LiteralValue::Number(n) if *n == 0.0 => Some(MemoKey::Number(0)),
+        LiteralValue::Number(n) => Some(MemoKey::Number(n.to_bits())),
+        LiteralValue::StringValue(s) => Some(MemoKey::StringValue(s.clone())),
+        LiteralValue::Bool(b) => Some(MemoKey::Bool(*b)),
+        LiteralValue::Nil => Some(MemoKey::Nil),
+        LiteralValue::Callable {
+            id,
+            name: _,
+            arity: _,
+            line: _,
+            fun: _,
+        } => Some(MemoKey::Callable(*id)),
+        LiteralValue::Class(_)
+        | LiteralValue::Instance {
+            class: _,
+            fields: _,
+        } => None,
+    }
+}
+
+// Wrap fun with a cache so repeated calls with the same arguments reuse the first result
+// Calls with an argument that has no MemoKey are not cached
+#[allow(clippy::type_complexity)]
+pub fn memoized(
+    fun: Rc<dyn Fn(&Vec<LiteralValue>) -> Result<LiteralValue, Box<dyn Error>>>,
+) -> Rc<dyn Fn(&Vec<LiteralValue>) -> Result<LiteralValue, Box<dyn Error>>> {
+    let cache: RefCell<HashMap<Vec<MemoKey>, LiteralValue>> = RefCell::new(HashMap::new());
+    Rc::new(
+        move |args: &Vec<LiteralValue>| -> Result<LiteralValue, Box<dyn Error>> {
+            let key: Option<Vec<MemoKey>> = args.iter().map(memo_key).collect();
+            let Some(key) = key else {
+                return fun(args);
+            };
+            if let Some(val) = cache.borrow().get(&key) {
+                return Ok(val.clone());
+            }
+            let val = fun(args)?;
+            cache.borrow_mut().insert(key, val.clone());
+            Ok(val)
+        },
+    )
+}
+
+// Memoize a callable from a script
+// Classes and instances can not be cache keys, calling it with one is an error
+#[allow(clippy::ptr_arg)]
+fn memoize_impl(args: &Vec<LiteralValue>) -> Result<LiteralValue, Box<dyn Error>> {
+    match &args[0] {
+        LiteralValue::Callable {
+            id: _,
+            name,
+            arity,
+            line,
+            fun,
+        } => {
+            let cached = memoized(fun.clone());
+            let memo_name = name.clone();
+            let checked = move |args: &Vec<LiteralValue>| {
+                if let Some(arg) = args.iter().find(|arg| memo_key(arg).is_none()) {
+                    return Err(format!(
+                        "Memoized '{}' got an unhashable argument of type {}",
+                        memo_name,
+                        arg.to_type()
+                    )
+                    .into());
+                }
+                cached(args)
+            };
+            Ok(LiteralValue::Callable {
+                id: next_callable_id(),
+                name: name.clone(),
+                arity: *arity,
+                line: *line,
+                fun: Rc::new(checked),
+            })
+        }
+        e => Err(format!("memoize expected Callable and got {}", e.to_type()).into()),
+    }
+}
+
+// Does nothing on its own, calls to it are intercepted in Expr::evaluvate to start the debugger
+#[allow(clippy::ptr_arg)]
+fn breakpoint_impl(_args: &Vec<LiteralValue>) -> Result<LiteralValue, Box<dyn Error>> {
+    Ok(LiteralValue::Nil)
+}
+
+// Print the value to stderr, formatted like the print statement
+#[allow(clippy::ptr_arg)]
+fn eprint_impl(args: &Vec<LiteralValue>) -> Result<LiteralValue, Box<dyn Error>> {
+    // Anything printed before has to show up first
+    output::flush()?;
+    eprintln!("{}", args[0].display_string());
+    Ok(LiteralValue::Nil)
+}
+
+// fallback if value is nil, otherwise value
+// Unlike 'or' a false value is kept, and as with any call both arguments are always evaluvated
+#[allow(clippy::ptr_arg)]
+fn default_impl(args: &Vec<LiteralValue>) -> Result<LiteralValue, Box<dyn Error>> {
+    match &args[0] {
+        LiteralValue::Nil => Ok(args[1].clone()),
+        value => Ok(value.clone()),
+    }
+}
+
+// Fill the %d, %f, %s and %% directives of fmt with args
+fn format_directives(fmt: &str, args: &[LiteralValue]) -> Result<String, Box<dyn Error>> {
+    let directives = fmt.matches('%').count() - 2 * fmt.matches("%%").count();
+    if directives != args.len() {
+        return Err(format!(
+            "printf format expects {} arguments and got {}",
+            directives,
+            args.len()
+        )
+        .into());
+    }
+    let mut out = String::new();
+    let mut args = args.iter();
+    let mut chars = fmt.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '%' => match chars.next() {
+                Some('%') => out.push('%'),
+                directive => match (directive, args.next()) {
+                    (Some('d'), Some(LiteralValue::Number(n))) if n.fract() == 0.0 => {
+                        out.push_str(&LiteralValue::format_number(*n))
+                    }
+                    (Some('f'), Some(LiteralValue::Number(n))) => {
+                        out.push_str(&format!("{:.6}", n))
+                    }
+                    (Some('s'), Some(LiteralValue::StringValue(s))) => out.push_str(s),
+                    (Some('s'), Some(value)) => out.push_str(&value.to_string()),
+                    (Some('d'), Some(value)) => {
+                        return Err(format!(
+                            "printf %d expected an integer and got {}",
+                            value.to_string()
+                        )
+                        .into())
+                    }
+                    (Some('f'), Some(value)) => {
+                        return Err(format!(
+                            "printf %f expected a Number and got {}",
+                            value.to_type()
+                        )
+                        .into())
+                    }
+                    (directive, _) => {
+                        return Err(format!(
+                            "printf got an unknown directive '%{}'",
+                            directive.map(String::from).unwrap_or_default()
+                        )
+                        .into())
+                    }
+                },
+            },
+            _ => out.push(c),
+        }
+    }
+    Ok(out)
+}
+
+// C style formatted print, no newline is added
+#[allow(clippy::ptr_arg)]
+fn printf_impl(args: &Vec<LiteralValue>) -> Result<LiteralValue, Box<dyn Error>> {
+    match &args[0] {
+        LiteralValue::StringValue(fmt) => {
+            output::write(&format_directives(fmt, &args[1..])?)?;
+            Ok(LiteralValue::Nil)
+        }
+        other => {
+            Err(format!("printf expected a String format and got {}", other.to_type()).into())
+        }
+    }
+}
+
+// The same as the print statement, used for it when print is parsed as a function
+#[allow(clippy::ptr_arg)]
+fn print_impl(args: &Vec<LiteralValue>) -> Result<LiteralValue, Box<dyn Error>> {
+    output::write_line(&args[0].display_string())?;
+    Ok(LiteralValue::Nil)
+}
+
+// Read a line from stdin without its line ending, nil at the end of input
+// The prompt is written first, and everything printed so far is flushed so it shows before
+// the program waits
+#[allow(clippy::ptr_arg)]
+fn input_impl(args: &Vec<LiteralValue>) -> Result<LiteralValue, Box<dyn Error>> {
+    if let Some(prompt) = args.first() {
+        output::write(&prompt.display_string())?;
+    }
+    output::flush()?;
+    let mut line = String::new();
+    if std::io::stdin().read_line(&mut line)? == 0 {
+        return Ok(LiteralValue::Nil);
+    }
+    let line = line.strip_suffix('\n').unwrap_or(&line);
+    let line = line.strip_suffix('\r').unwrap_or(line);
+    Ok(LiteralValue::StringValue(Rc::from(line)))
+}
+
+// print without the newline, flushed right away so partial lines such as prompts show up
+#[allow(clippy::ptr_arg)]
+fn write_impl(args: &Vec<LiteralValue>) -> Result<LiteralValue, Box<dyn Error>> {
+    output::write(&args[0].display_string())?;
+    output::flush()?;
+    Ok(LiteralValue::Nil)
+}
+
+thread_local! {
+    // State of the xorshift generator behind rand_int and rand_float
+    // Seeded from the clock, seed() makes the numbers reproducible
+    static RNG: Cell<u64> = Cell::new(rng_state(
+        std::time::SystemTime::now()
+            .duration_since(std::time::SystemTime::UNIX_EPOCH)
+            .expect("Could not get system time")
+            .as_nanos() as u64,
+    ));
+}
+
+// xorshift gets stuck on 0 so the seed is mixed into a state that never is
+fn rng_state(seed: u64) -> u64 {
+    (seed ^ 0x9E37_79B9_7F4A_7C15) | 1
+}
+
+// Next number in [0, 1)
+fn next_random() -> f64 {
+    RNG.with(|state| {
+        let mut x = state.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        state.set(x);
+        (x >> 11) as f64 / (1u64 << 53) as f64
+    })
+}
+
+// Restart the generator so the same seed gives the same numbers
+#[allow(clippy::ptr_arg)]
+fn seed_impl(args: &Vec<LiteralValue>) -> Result<LiteralValue, Box<dyn Error>> {
+    match &args[0] {
+        LiteralValue::Number(n) if n.fract() == 0.0 => {
+            RNG.with(|state| state.set(rng_state(*n as i64 as u64)));
+            Ok(LiteralValue::Nil)
+        }
+        other => Err(format!("seed expected an integer and got {}", other.to_string()).into()),
+    }
+}
+
+// The lo and hi bounds of rand_int and rand_float, lo can not be above hi
+fn random_range(name: &str, args: &[LiteralValue]) -> Result<(f64, f64), Box<dyn Error>> {
+    match (&args[0], &args[1]) {
+        (LiteralValue::Number(lo), LiteralValue::Number(hi)) if lo <= hi => Ok((*lo, *hi)),
+        (LiteralValue::Number(lo), LiteralValue::Number(hi)) => {
+            Err(format!("{} expected lo <= hi and got {} and {}", name, lo, hi).into())
+        }
+        (lo, hi) => Err(format!(
+            "{} expected Numbers and got {} and {}",
+            name,
+            lo.to_type(),
+            hi.to_type()
+        )
+        .into()),
+    }
+}
+
+// Whole number in [lo, hi], both ends included
+#[allow(clippy::ptr_arg)]
+fn rand_int_impl(args: &Vec<LiteralValue>) -> Result<LiteralValue, Box<dyn Error>> {
+    let (lo, hi) = random_range("rand_int", args)?;
+    if lo.fract() != 0.0 || hi.fract() != 0.0 {
+        return Err(format!("rand_int expected integers and got {} and {}", lo, hi).into());
+    }
+    Ok(LiteralValue::Number(lo + (next_random() * (hi - lo + 1.0)).floor()))
+}
+
+// Number in [lo, hi)
+#[allow(clippy::ptr_arg)]
+fn rand_float_impl(args: &Vec<LiteralValue>) -> Result<LiteralValue, Box<dyn Error>> {
+    let (lo, hi) = random_range("rand_float", args)?;
+    Ok(LiteralValue::Number(lo + next_random() * (hi - lo)))
+}
+
+#[allow(clippy::type_complexity)]
+fn define_native(
+    env: &mut HashMap<String, LiteralValue>,
+    name: &str,
+    arity: impl Into<AritySpec>,
+    fun: Rc<dyn Fn(&Vec<LiteralValue>) -> Result<LiteralValue, Box<dyn Error>>>,
+) {
     env.insert(
-        "clock".to_string(),
+        name.to_string(),
         LiteralValue::Callable {
-            name: "clock".to_string(),
-            arity: 0,
-            fun: Rc::new(clock_impl),
+            id: next_callable_id(),
+            name: name.to_string(),
+            arity: arity.into(),
+            line: 0,
+            fun,
         },
     );
+}
+
+// A native applying f to its one Number argument
+#[allow(clippy::type_complexity)]
+fn unary_math(
+    name: &'static str,
+    f: fn(f64) -> f64,
+) -> Rc<dyn Fn(&Vec<LiteralValue>) -> Result<LiteralValue, Box<dyn Error>>> {
+    Rc::new(move |args: &Vec<LiteralValue>| match &args[0] {
+        LiteralValue::Number(x) => Ok(LiteralValue::Number(f(*x))),
+        e => Err(format!("{} expected Number and got {}", name, e.to_type()).into()),
+    })
+}
+
+// A native applying f to its two Number arguments
+#[allow(clippy::type_complexity)]
+fn binary_math(
+    name: &'static str,
+    f: fn(f64, f64) -> f64,
+) -> Rc<dyn Fn(&Vec<LiteralValue>) -> Result<LiteralValue, Box<dyn Error>>> {
+    Rc::new(move |args: &Vec<LiteralValue>| match (&args[0], &args[1]) {
+        (LiteralValue::Number(a), LiteralValue::Number(b)) => Ok(LiteralValue::Number(f(*a, *b))),
+        (a, b) => Err(format!(
+            "{} expected (Number, Number) and got ({}, {})",
+            name,
+            a.to_type(),
+            b.to_type()
+        )
+        .into()),
+    })
+}
+
+// Thin wrappers over the f64 methods, angles are in radians
+fn define_math(env: &mut HashMap<String, LiteralValue>) {
+    define_native(env, "sqrt", 1, unary_math("sqrt", f64::sqrt));
+    define_native(env, "abs", 1, unary_math("abs", f64::abs));
+    define_native(env, "floor", 1, unary_math("floor", f64::floor));
+    define_native(env, "ceil", 1, unary_math("ceil", f64::ceil));
+    define_native(env, "round", 1, unary_math("round", f64::round));
+    define_native(env, "sin", 1, unary_math("sin", f64::sin));
+    define_native(env, "cos", 1, unary_math("cos", f64::cos));
+    define_native(env, "tan", 1, unary_math("tan", f64::tan));
+    define_native(env, "pow", 2, binary_math("pow", f64::powf));
+    define_native(env, "min", 2, binary_math("min", f64::min));
+    define_native(env, "max", 2, binary_math("max", f64::max));
+}
+
+thread_local! {
+    // The natives are built once so every Environment shares the same Callables
+    static NATIVES: HashMap<String, LiteralValue> = build_natives();
+}
+
+// Whether the Callable with this id and name is one of the natives
+pub fn is_native(id: usize, name: &str) -> bool {
+    NATIVES.with(|natives| {
+        matches!(
+            natives.get(name),
+            Some(LiteralValue::Callable {
+                id: native,
+                name: _,
+                arity: _,
+                line: _,
+                fun: _,
+            }) if *native == id
+        )
+    })
+}
+
+fn get_globals() -> HashMap<String, LiteralValue> {
+    NATIVES.with(|natives| natives.clone())
+}
+
+fn build_natives() -> HashMap<String, LiteralValue> {
+    let mut env = HashMap::new();
+    define_math(&mut env);
+    define_native(&mut env, "clock", 0, Rc::new(clock_impl));
+    define_native(&mut env, "time_it", 1, Rc::new(time_it_impl));
+    define_native(&mut env, "char_at", 2, Rc::new(char_at_impl));
+    define_native(&mut env, "char_code", 1, Rc::new(char_code_impl));
+    define_native(&mut env, "len", 1, Rc::new(len_impl));
+    define_native(&mut env, "to_number", 1, Rc::new(to_number_impl));
+    define_native(&mut env, "to_string", 1, Rc::new(to_string_impl));
+    define_native(&mut env, "substr", 3, Rc::new(substr_impl));
+    define_native(&mut env, "upper", 1, Rc::new(upper_impl));
+    define_native(&mut env, "lower", 1, Rc::new(lower_impl));
+    define_native(&mut env, "from_char_code", 1, Rc::new(from_char_code_impl));
+    define_native(&mut env, "eprint", 1, Rc::new(eprint_impl));
+    define_native(&mut env, "breakpoint", 0, Rc::new(breakpoint_impl));
+    define_native(&mut env, "group", 1, Rc::new(group_impl));
+    define_native(&mut env, "clamp", 3, Rc::new(clamp_impl));
+    define_native(&mut env, "lerp", 3, Rc::new(lerp_impl));
+    define_native(&mut env, "abs_diff", 2, Rc::new(abs_diff_impl));
+    define_native(&mut env, "sign", 1, Rc::new(sign_impl));
+    define_native(&mut env, "memoize", 1, Rc::new(memoize_impl));
+    define_native(&mut env, "gcd", 2, Rc::new(gcd_impl));
+    define_native(&mut env, "lcm", 2, Rc::new(lcm_impl));
+    define_native(&mut env, "wrap_add", 3, Rc::new(wrap_add_impl));
+    define_native(&mut env, "wrap_sub", 3, Rc::new(wrap_sub_impl));
+    define_native(&mut env, "to_base", 2, Rc::new(to_base_impl));
+    define_native(&mut env, "from_base", 2, Rc::new(from_base_impl));
+    define_native(&mut env, "default", 2, Rc::new(default_impl));
+    define_native(&mut env, "check_type", 2, Rc::new(check_type_impl));
+    define_native(&mut env, "print", 1, Rc::new(print_impl));
+    define_native(&mut env, "write", 1, Rc::new(write_impl));
+    define_native(&mut env, "input", AritySpec::range(0, 1), Rc::new(input_impl));
+    define_native(&mut env, "seed", 1, Rc::new(seed_impl));
+    define_native(&mut env, "rand_int", 2, Rc::new(rand_int_impl));
+    define_native(&mut env, "rand_float", 2, Rc::new(rand_float_impl));
+    define_native(&mut env, "printf", AritySpec::at_least(1), Rc::new(printf_impl));
+    define_native(
+        &mut env,
+        "assert_approx",
+        AritySpec::range(2, 3),
+        Rc::new(assert_approx_impl),
+    );
     env
 }
 
+// Number of single char inserts, deletes and substitutions to turn a into b
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut row = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { 0 } else { 1 };
+            row.push((prev[j] + cost).min(prev[j + 1] + 1).min(row[j] + 1));
+        }
+        prev = row;
+    }
+    prev[b.len()]
+}
+
 impl Environment {
     pub fn new() -> Self {
         Environment {
             values: HashMap::<String, LiteralValue>::new(),
             globals: get_globals(),
+            types: HashMap::new(),
+            global_types: HashMap::new(),
+            deferred: HashMap::new(),
+            global_deferred: HashMap::new(),
             enclosing: None,
         }
     }
 
+    // A scope inside parent, only the outermost Environment holds the globals and natives so
+    // this one starts empty
+    pub fn new_enclosed(parent: Rc<RefCell<Environment>>) -> Self {
+        Environment {
+            values: HashMap::new(),
+            globals: HashMap::new(),
+            types: HashMap::new(),
+            global_types: HashMap::new(),
+            deferred: HashMap::new(),
+            global_deferred: HashMap::new(),
+            enclosing: Some(parent),
+        }
+    }
+
     // create a new variable or override a existing variable of same name
+    // Globals (distance None) always live in the outermost Environment
     pub fn define(&mut self, name: String, value: LiteralValue, distance: Option<usize>) {
-        if distance.is_none() {
-            self.globals.insert(name, value);
-        } else {
-            let distance = distance.unwrap();
-            if distance == 0 {
+        match distance {
+            None => match &self.enclosing {
+                Some(enclosing) => enclosing.borrow_mut().define(name, value, None),
+                None => {
+                    // A redeclaration drops the old type and initializer
+                    self.global_types.remove(&name);
+                    self.global_deferred.remove(&name);
+                    self.globals.insert(name, value);
+                }
+            },
+            Some(0) => {
+                self.types.remove(&name);
+                self.deferred.remove(&name);
                 self.values.insert(name, value);
-            } else {
-                self.define(name, value, Some(distance - 1));
             }
+            Some(distance) => match self.ancestor(distance) {
+                None => panic!(
+                    "Tried to define a var that was defined deeper than the current env depth"
+                ),
+                Some(env) => env.borrow_mut().define(name, value, Some(0)),
+            },
+        }
+    }
+
+    // Distance to define a new variable of the current scope at, the outermost scope keeps its
+    // variables as globals
+    pub fn scope_distance(&self) -> Option<usize> {
+        self.enclosing.as_ref().map(|_| 0)
+    }
+
+    // Record the type a variable was declared with, define it first
+    pub fn annotate(&mut self, name: &str, type_name: &str, distance: Option<usize>) {
+        match (distance, &self.enclosing) {
+            (None, Some(enclosing)) => enclosing.borrow_mut().annotate(name, type_name, None),
+            (None, None) => {
+                self.global_types.insert(name.to_string(), type_name.to_string());
+            }
+            (Some(0), _) => {
+                self.types.insert(name.to_string(), type_name.to_string());
+            }
+            (Some(distance), _) => {
+                if let Some(env) = self.ancestor(distance) {
+                    env.borrow_mut().annotate(name, type_name, Some(0));
+                }
+            }
+        }
+    }
+
+    // Give a defined variable an initializer that runs on its first read, see expr::read_var
+    pub fn defer(&mut self, name: &str, thunk: Thunk, distance: Option<usize>) {
+        match (distance, &self.enclosing) {
+            (None, Some(enclosing)) => enclosing.borrow_mut().defer(name, thunk, None),
+            (None, None) => {
+                self.global_deferred.insert(name.to_string(), thunk);
+            }
+            (Some(0), _) => {
+                self.deferred.insert(name.to_string(), thunk);
+            }
+            (Some(distance), _) => {
+                if let Some(env) = self.ancestor(distance) {
+                    env.borrow_mut().defer(name, thunk, Some(0));
+                }
+            }
+        }
+    }
+
+    // Remove and return the initializer of a lazy var that has not been read yet
+    pub fn take_deferred(&mut self, name: &str, distance: Option<usize>) -> Option<Thunk> {
+        match (distance, &self.enclosing) {
+            (None, Some(enclosing)) => enclosing.borrow_mut().take_deferred(name, None),
+            (None, None) => self.global_deferred.remove(name),
+            (Some(0), _) => self.deferred.remove(name),
+            (Some(distance), _) => self
+                .ancestor(distance)
+                .and_then(|env| env.borrow_mut().take_deferred(name, Some(0))),
+        }
+    }
+
+    // The type a variable was declared with if it had one
+    pub fn annotation(&self, name: &str, distance: Option<usize>) -> Option<String> {
+        match distance {
+            None => match &self.enclosing {
+                Some(enclosing) => enclosing.borrow().annotation(name, None),
+                None => self.global_types.get(name).cloned(),
+            },
+            Some(0) => self.types.get(name).cloned(),
+            Some(distance) => match self.ancestor(distance) {
+                None => panic!(
+                    "Tried to resolve a var that was defined deeper than the current env depth"
+                ),
+                Some(env) => env.borrow().annotation(name, Some(0)),
+            },
         }
     }
 
     // Assign a value to a already existing variable
     pub fn assign(&mut self, name: &str, value: LiteralValue, distance: Option<usize>) -> bool {
-        if distance.is_none() {
-            self.globals.insert(name.to_string(), value);
-            true
-        } else {
-            let distance = distance.unwrap();
-            if distance == 0 {
+        match distance {
+            None => match &self.enclosing {
+                Some(enclosing) => enclosing.borrow_mut().assign(name, value, None),
+                None => {
+                    // Assigning before the first read means the initializer is never needed
+                    self.global_deferred.remove(name);
+                    self.globals.insert(name.to_string(), value);
+                    true
+                }
+            },
+            Some(0) => {
+                self.deferred.remove(name);
                 self.values.insert(name.to_string(), value.clone());
                 true
-            } else {
-                match &self.enclosing {
-                    None => panic!(
-                        "Tried to assign a var that was defined deeper than the current env depth"
-                    ),
-                    Some(env) => return env.borrow_mut().assign(name, value, Some(distance - 1)),
+            }
+            Some(distance) => match self.ancestor(distance) {
+                None => panic!(
+                    "Tried to assign a var that was defined deeper than the current env depth"
+                ),
+                Some(env) => env.borrow_mut().assign(name, value, Some(0)),
+            },
+        }
+    }
+
+    // Clone the bindings of the current scope so they can be rolled back later
+    // Globals are not part of the snapshot
+    #[allow(dead_code)]
+    pub fn snapshot(&self) -> HashMap<String, LiteralValue> {
+        self.values.clone()
+    }
+
+    // Replace the bindings of the current scope with a previously taken snapshot
+    #[allow(dead_code)]
+    pub fn restore(&mut self, snapshot: HashMap<String, LiteralValue>) {
+        self.values = snapshot;
+    }
+
+    // Collect every binding visible from this Environment into a new Environment
+    // Inner bindings shadow outer ones, the result does not write back to the original
+    pub fn flatten(&self) -> Environment {
+        let mut flat = match &self.enclosing {
+            Some(env) => env.borrow().flatten(),
+            None => Environment {
+                values: HashMap::new(),
+                globals: HashMap::new(),
+                types: HashMap::new(),
+                global_types: HashMap::new(),
+                deferred: HashMap::new(),
+                global_deferred: HashMap::new(),
+                enclosing: None,
+                },
+        };
+        for (name, value) in &self.globals {
+            if !flat.globals.contains_key(name) {
+                flat.globals.insert(name.clone(), value.clone());
+                if let Some(type_name) = self.global_types.get(name) {
+                    flat.global_types.insert(name.clone(), type_name.clone());
                 }
             }
         }
+        for name in self.values.keys() {
+            match self.types.get(name) {
+                Some(type_name) => flat.global_types.insert(name.clone(), type_name.clone()),
+                None => flat.global_types.remove(name),
+            };
+        }
+        flat.globals.extend(self.values.clone());
+        flat
+    }
+
+    // How many scopes up from this one name is defined, None if only a global has it
+    pub fn depth_of(&self, name: &str) -> Option<usize> {
+        if self.values.contains_key(name) {
+            return Some(0);
+        }
+        let depth = self.enclosing.as_ref()?.borrow().depth_of(name)?;
+        Some(depth + 1)
+    }
+
+    // The visible name closest to the given one, if any is close enough to be a likely typo
+    pub fn suggest(&self, name: &str) -> Option<String> {
+        let max_distance = (name.chars().count() / 3).max(1);
+        let flat = self.flatten();
+        let mut names: Vec<&String> = flat.globals.keys().collect();
+        // Sort first so ties always pick the same name
+        names.sort();
+        names
+            .into_iter()
+            .map(|candidate| (levenshtein(name, candidate), candidate))
+            .filter(|(distance, _)| *distance > 0 && *distance <= max_distance)
+            .min_by_key(|(distance, _)| *distance)
+            .map(|(_, candidate)| candidate.clone())
     }
 
     // Get the value of a variable
     pub fn get(&self, name: &str, distance: Option<usize>) -> Option<LiteralValue> {
-        if distance.is_none() {
-            self.globals.get(name).cloned()
+        match distance {
+            None => match &self.enclosing {
+                Some(enclosing) => enclosing.borrow().get(name, None),
+                None => self.globals.get(name).cloned(),
+            },
+            Some(0) => self.values.get(name).cloned(),
+            Some(distance) => match self.ancestor(distance) {
+                None => panic!(
+                    "Tried to resolve a var that was defined deeper than the current env depth"
+                ),
+                Some(env) => env.borrow().get(name, Some(0)),
+            },
+        }
+    }
+
+    // The Environment distance levels up the enclosing chain
+    pub fn ancestor(&self, distance: usize) -> Option<Rc<RefCell<Environment>>> {
+        let mut env = self.enclosing.clone()?;
+        for _ in 1..distance {
+            let next = env.borrow().enclosing.clone()?;
+            env = next;
+        }
+        Some(env)
+    }
+}
+
+type WeakEnv = Weak<RefCell<Environment>>;
+
+thread_local! {
+    // Per expression id, the Environment it was last evaluvated in and the one its variable was
+    // found in. enclosing is only set when an Environment is made so the pair stays valid, and
+    // the Weak keeps the first one's address from being reused
+    static RESOLVED: RefCell<HashMap<usize, (WeakEnv, WeakEnv)>> = RefCell::new(HashMap::new());
+}
+
+// The Environment the variable of expression id lives in and its distance from there
+// Evaluvating the expression again in the same Environment skips walking the chain
+pub fn resolve_scope(
+    id: usize,
+    env: &Rc<RefCell<Environment>>,
+    distance: Option<usize>,
+) -> (Rc<RefCell<Environment>>, Option<usize>) {
+    let distance = match distance {
+        Some(0) | None => return (env.clone(), distance),
+        Some(distance) => distance,
+    };
+    let cached = RESOLVED.with(|resolved| {
+        let resolved = resolved.borrow();
+        let (from, to) = resolved.get(&id)?;
+        if from.as_ptr() == Rc::as_ptr(env) {
+            to.upgrade()
         } else {
-            let distance = distance.unwrap();
-            if distance == 0 {
-                self.values.get(name).cloned()
-            } else {
-                match &self.enclosing {
-                    None => panic!(
-                        "Tried to resolve a var that was defined deeper than the current env depth"
-                    ),
-                    Some(env) => env.borrow().get(name, Some(distance - 1)),
-                }
-            }
+            None
+        }
+    });
+    if let Some(scope) = cached {
+        return (scope, Some(0));
+    }
+    match env.borrow().ancestor(distance) {
+        Some(scope) => {
+            RESOLVED.with(|resolved| {
+                resolved
+                    .borrow_mut()
+                    .insert(id, (Rc::downgrade(env), Rc::downgrade(&scope)))
+            });
+            (scope, Some(0))
         }
+        // Left for get and assign to report
+        None => (env.clone(), Some(distance)),
     }
 }
 
@@ -104,4 +1264,162 @@ mod tests {
     fn try_init() {
         let _env = Environment::new();
     }
+
+    #[test]
+    fn enclosed_scopes_share_the_root_natives() {
+        let root = Rc::new(RefCell::new(Environment::new()));
+        let inner = Environment::new_enclosed(root.clone());
+        assert!(inner.globals.is_empty());
+        assert!(inner.get("clock", None).is_some());
+    }
+
+    #[test]
+    fn snapshot_restore() {
+        let mut env = Environment::new();
+        env.define("a".to_string(), LiteralValue::Number(1.0), Some(0));
+
+        let snapshot = env.snapshot();
+        env.assign("a", LiteralValue::Number(2.0), Some(0));
+        env.define("b".to_string(), LiteralValue::Number(3.0), Some(0));
+        assert_eq!(env.get("a", Some(0)), Some(LiteralValue::Number(2.0)));
+
+        env.restore(snapshot);
+        assert_eq!(env.get("a", Some(0)), Some(LiteralValue::Number(1.0)));
+        assert_eq!(env.get("b", Some(0)), None);
+    }
+
+    #[test]
+    fn suggest_similar_names() {
+        assert_eq!(levenshtein("cout", "count"), 1);
+        assert_eq!(levenshtein("kitten", "sitting"), 3);
+
+        let (_root, env) = nested(2);
+        env.borrow_mut()
+            .define("count".to_string(), LiteralValue::Nil, Some(0));
+        assert_eq!(env.borrow().suggest("cout"), Some("count".to_string()));
+        // Names from enclosing scopes and natives are suggested too
+        assert_eq!(env.borrow().suggest("b"), Some("a".to_string()));
+        assert_eq!(env.borrow().suggest("clok"), Some("clock".to_string()));
+        assert_eq!(env.borrow().suggest("something_else"), None);
+    }
+
+    #[test]
+    fn gcd_lcm_reject_non_integers() {
+        let args = vec![LiteralValue::Number(1.5), LiteralValue::Number(3.0)];
+        assert_eq!(
+            gcd_impl(&args).unwrap_err().to_string(),
+            "gcd expected integers and got 1.5"
+        );
+        let args = vec![LiteralValue::Number(4.0), LiteralValue::Bool(true)];
+        assert_eq!(
+            lcm_impl(&args).unwrap_err().to_string(),
+            "lcm expected (Number, Number) and got (Number, Boolean)"
+        );
+    }
+
+    #[test]
+    fn printf_directives() {
+        let args = vec![
+            LiteralValue::Number(1.0),
+            LiteralValue::StringValue(Rc::from("a")),
+            LiteralValue::Number(0.5),
+        ];
+        assert_eq!(
+            format_directives("%d%% %s %f", &args).unwrap(),
+            "1% a 0.500000"
+        );
+        assert_eq!(
+            format_directives("%d", &[]).unwrap_err().to_string(),
+            "printf format expects 1 arguments and got 0"
+        );
+        assert_eq!(
+            format_directives("%d", &[LiteralValue::Number(1.5)])
+                .unwrap_err()
+                .to_string(),
+            "printf %d expected an integer and got 1.5"
+        );
+        assert_eq!(
+            format_directives("%f", &[LiteralValue::Bool(true)])
+                .unwrap_err()
+                .to_string(),
+            "printf %f expected a Number and got Boolean"
+        );
+        assert_eq!(
+            format_directives("%x", &[LiteralValue::Nil])
+                .unwrap_err()
+                .to_string(),
+            "printf got an unknown directive '%x'"
+        );
+    }
+
+    #[test]
+    fn wrap_rejects_bad_modulus() {
+        let args = vec![
+            LiteralValue::Number(1.0),
+            LiteralValue::Number(2.0),
+            LiteralValue::Number(0.0),
+        ];
+        assert_eq!(
+            wrap_add_impl(&args).unwrap_err().to_string(),
+            "wrap_add expected a positive modulus and got 0"
+        );
+        let args = vec![
+            LiteralValue::Number(1.0),
+            LiteralValue::Number(2.0),
+            LiteralValue::Number(2.5),
+        ];
+        assert_eq!(
+            wrap_sub_impl(&args).unwrap_err().to_string(),
+            "wrap_sub expected integers and got 2.5"
+        );
+    }
+
+    // Chain of depth Environments with a at the root
+    fn nested(depth: usize) -> (Rc<RefCell<Environment>>, Rc<RefCell<Environment>>) {
+        let root = Rc::new(RefCell::new(Environment::new()));
+        root.borrow_mut()
+            .define("a".to_string(), LiteralValue::Number(1.0), Some(0));
+        let mut env = root.clone();
+        for _ in 0..depth {
+            env = Rc::new(RefCell::new(Environment::new_enclosed(env)));
+        }
+        (root, env)
+    }
+
+    #[test]
+    fn cached_ancestor_after_restore() {
+        let (root, env) = nested(3);
+        assert_eq!(env.borrow().get("a", Some(3)), Some(LiteralValue::Number(1.0)));
+
+        let snapshot = root.borrow().snapshot();
+        assert!(env
+            .borrow_mut()
+            .assign("a", LiteralValue::Number(2.0), Some(3)));
+        assert_eq!(root.borrow().get("a", Some(0)), Some(LiteralValue::Number(2.0)));
+
+        root.borrow_mut().restore(snapshot);
+        assert_eq!(env.borrow().get("a", Some(3)), Some(LiteralValue::Number(1.0)));
+        assert_eq!(env.borrow().get("a", Some(2)), None);
+    }
+
+    #[test]
+    fn resolved_scope_after_restore() {
+        let id = crate::expr::next_expr_id();
+        let (root, env) = nested(3);
+        let (scope, distance) = resolve_scope(id, &env, Some(3));
+        assert!(Rc::ptr_eq(&scope, &root));
+        assert_eq!(distance, Some(0));
+
+        let snapshot = root.borrow().snapshot();
+        root.borrow_mut()
+            .assign("a", LiteralValue::Number(2.0), Some(0));
+        root.borrow_mut().restore(snapshot);
+        let (scope, distance) = resolve_scope(id, &env, Some(3));
+        assert_eq!(scope.borrow().get("a", distance), Some(LiteralValue::Number(1.0)));
+
+        // The same expression in another chain is resolved again
+        let (other_root, other_env) = nested(3);
+        let (scope, _) = resolve_scope(id, &other_env, Some(3));
+        assert!(Rc::ptr_eq(&scope, &other_root));
+    }
 }
@@ -1,6 +1,10 @@
 use super::scanner::Token;
-use crate::{environments::Environment, interpreter::Interpreter, scanner, stmt::Stmt, TokenType};
+use crate::interpreter::{Interpreter, Locals, Options, Returned};
+use crate::environments::{is_native, resolve_scope, Environment, DEFAULT_EPSILON};
+use crate::{scanner, stmt::Stmt, TokenType};
+use std::collections::HashMap;
 use std::hash::Hasher;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::{cell::RefCell, error::Error, hash::Hash, rc::Rc};
 
 // unwraping helper function
@@ -19,18 +23,106 @@ fn unwrap_as_string(literal: Option<scanner::LiteralValue>) -> String {
     }
 }
 
+static NEXT_CALLABLE_ID: AtomicUsize = AtomicUsize::new(0);
+
+// Get a fresh id for a new Callable
+pub fn next_callable_id() -> usize {
+    NEXT_CALLABLE_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+static NEXT_EXPR_ID: AtomicUsize = AtomicUsize::new(0);
+
+// Get a fresh id for a new Variable or Assign, the resolved distances are keyed on it as it
+// stays the same when the Expr is cloned into a function
+pub fn next_expr_id() -> usize {
+    NEXT_EXPR_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+// How many arguments a Callable accepts, max is None for variadic callables
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AritySpec {
+    pub min: usize,
+    pub max: Option<usize>,
+}
+
+impl AritySpec {
+    pub fn exact(n: usize) -> Self {
+        AritySpec {
+            min: n,
+            max: Some(n),
+        }
+    }
+
+    pub fn range(min: usize, max: usize) -> Self {
+        AritySpec {
+            min,
+            max: Some(max),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn at_least(min: usize) -> Self {
+        AritySpec { min, max: None }
+    }
+
+    // Check if a call with n arguments is valid
+    pub fn accepts(&self, n: usize) -> bool {
+        n >= self.min && self.max.is_none_or(|max| n <= max)
+    }
+}
+
+impl From<usize> for AritySpec {
+    fn from(n: usize) -> Self {
+        AritySpec::exact(n)
+    }
+}
+
+impl std::fmt::Display for AritySpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.max {
+            Some(max) if max == self.min => write!(f, "{}", self.min),
+            Some(max) => write!(f, "{}..{}", self.min, max),
+            None => write!(f, "{}..", self.min),
+        }
+    }
+}
+
+// Makes the Callable of a method with 'this' bound to the given instance
+pub type MethodBinder = Rc<dyn Fn(&LiteralValue) -> LiteralValue>;
+
+// Gives the default of a field for the instance being made
+pub type FieldInit = Rc<dyn Fn(&LiteralValue) -> Result<LiteralValue, Box<dyn Error>>>;
+
+// A class declaration, shared by the Class value and every instance of it
+pub struct ClassDef {
+    pub name: String,
+    // In the order they were declared
+    pub fields: Vec<(String, FieldInit)>,
+    pub methods: HashMap<String, MethodBinder>,
+}
+
 #[derive(Clone)]
 pub enum LiteralValue {
     Number(f64),
-    StringValue(String),
-    True,
-    False,
+    // Shared so cloning a string is a refcount bump
+    StringValue(Rc<str>),
+    Bool(bool),
     Nil,
     Callable {
+        // Unique per created Callable so equality is identity
+        id: usize,
         name: String,
-        arity: usize,
+        arity: AritySpec,
+        // Line the function was defined on, 0 for natives which have no source
+        line: usize,
         #[allow(clippy::type_complexity)]
-        fun: Rc<dyn Fn(&Vec<LiteralValue>) -> LiteralValue>,
+        fun: Rc<dyn Fn(&Vec<LiteralValue>) -> Result<LiteralValue, Box<dyn Error>>>,
+    },
+    Class(Rc<ClassDef>),
+    Instance {
+        class: Rc<ClassDef>,
+        // Shared so a field set through one copy of the instance is seen by all of them
+        fields: Rc<RefCell<HashMap<String, LiteralValue>>>,
     },
 }
 
@@ -45,58 +137,113 @@ impl PartialEq for LiteralValue {
         match (self, other) {
             (LiteralValue::Number(x), LiteralValue::Number(y)) => x == y,
             (LiteralValue::StringValue(x), LiteralValue::StringValue(y)) => x == y,
-            (LiteralValue::False, LiteralValue::False) => true,
-            (LiteralValue::True, LiteralValue::True) => true,
-            (LiteralValue::False, LiteralValue::True) => false,
-            (LiteralValue::True, LiteralValue::False) => false,
+            (LiteralValue::Bool(x), LiteralValue::Bool(y)) => x == y,
             (LiteralValue::Nil, LiteralValue::Nil) => true,
+            // Callables are only equal to themselves
             (
                 LiteralValue::Callable {
-                    name,
-                    arity,
+                    id,
+                    name: _,
+                    arity: _,
+                    line: _,
                     fun: _,
                 },
                 LiteralValue::Callable {
-                    name: name2,
-                    arity: arity2,
+                    id: id2,
+                    name: _,
+                    arity: _,
+                    line: _,
                     fun: _,
                 },
-            ) => name == name2 && arity == arity2,
-            _ => {
-                panic!("Error in PartialEq of LiteralValue")
-            }
+            ) => id == id2,
+            // Classes and instances too
+            (LiteralValue::Class(x), LiteralValue::Class(y)) => Rc::ptr_eq(x, y),
+            (
+                LiteralValue::Instance { class: _, fields },
+                LiteralValue::Instance {
+                    class: _,
+                    fields: fields2,
+                },
+            ) => Rc::ptr_eq(fields, fields2),
+            // Values of different types are never equal
+            _ => false,
         }
     }
 }
 
 #[allow(clippy::inherent_to_string)]
 impl LiteralValue {
+    // Display a number, whole numbers have no '.0', -0 shows up as 0 and the non finite values
+    // as words
+    // Everything that shows a number to the user goes through here
+    pub fn format_number(n: f64) -> String {
+        if n.is_nan() {
+            "NaN".to_string()
+        } else if n.is_infinite() {
+            if n > 0.0 { "Infinity" } else { "-Infinity" }.to_string()
+        } else if n == 0.0 {
+            "0".to_string()
+        } else {
+            n.to_string()
+        }
+    }
+
     pub fn to_string(&self) -> String {
         match self {
-            LiteralValue::Number(n) => n.to_string(),
+            LiteralValue::Number(n) => LiteralValue::format_number(*n),
             LiteralValue::StringValue(s) => format!("\"{}\"", s),
-            LiteralValue::True => "true".to_string(),
-            LiteralValue::False => "false".to_string(),
+            LiteralValue::Bool(b) => b.to_string(),
             LiteralValue::Nil => "nil".to_string(),
             LiteralValue::Callable {
+                id: _,
                 name,
                 arity,
+                line: 0,
                 fun: _,
             } => format!("<fn {}>/{}", name, arity),
+            LiteralValue::Callable {
+                id: _,
+                name,
+                arity,
+                line,
+                fun: _,
+            } => format!("<fn {} at line {}>/{}", name, line, arity),
+            LiteralValue::Class(class) => format!("<class {}>", class.name),
+            LiteralValue::Instance { class, fields: _ } => format!("<{} instance>", class.name),
+        }
+    }
+
+    // What print shows, strings without the quotes to_string puts around them
+    pub fn display_string(&self) -> String {
+        match self {
+            LiteralValue::StringValue(s) => s.to_string(),
+            value => value.to_string(),
         }
     }
 
+    // The value followed by its type, used by the REPL's '.inspect'
+    pub fn inspect(&self) -> String {
+        format!("{} : {}", self.to_string(), self.to_type())
+    }
+
     pub fn to_type(&self) -> &str {
         match self {
             LiteralValue::Number(_) => "Number",
             LiteralValue::StringValue(_) => "String",
-            LiteralValue::True | LiteralValue::False => "Boolean",
+            LiteralValue::Bool(_) => "Boolean",
             LiteralValue::Nil => "Nil",
             LiteralValue::Callable {
+                id: _,
                 name: _,
                 arity: _,
+                line: _,
                 fun: _,
             } => "Callable",
+            LiteralValue::Class(_) => "Class",
+            LiteralValue::Instance {
+                class: _,
+                fields: _,
+            } => "Instance",
         }
     }
 
@@ -104,9 +251,11 @@ impl LiteralValue {
     pub fn from_token(token: &Token) -> Self {
         match token.token_type {
             TokenType::Number => Self::Number(unwrap_as_f64(token.literal.clone())),
-            TokenType::String_ => Self::StringValue(unwrap_as_string(token.literal.clone())),
-            TokenType::True => Self::True,
-            TokenType::False => Self::False,
+            TokenType::String_ => {
+                Self::StringValue(Rc::from(unwrap_as_string(token.literal.clone())))
+            }
+            TokenType::True => Self::Bool(true),
+            TokenType::False => Self::Bool(false),
             TokenType::Nil => Self::Nil,
             _ => panic!("Cannot create literal from {:?}", token),
         }
@@ -115,113 +264,436 @@ impl LiteralValue {
     // Check is a given TokenType is False
     pub fn is_falsy(&self) -> LiteralValue {
         match self {
-            LiteralValue::Number(e) => {
-                if *e == 0. {
-                    LiteralValue::True
-                } else {
-                    LiteralValue::False
-                }
-            }
-            LiteralValue::StringValue(s) => {
-                if s.is_empty() {
-                    LiteralValue::True
-                } else {
-                    LiteralValue::False
-                }
-            }
-            LiteralValue::False => LiteralValue::True,
-            LiteralValue::True => LiteralValue::False,
-            LiteralValue::Nil => LiteralValue::True,
+            LiteralValue::Number(e) => LiteralValue::Bool(*e == 0.),
+            LiteralValue::StringValue(s) => LiteralValue::Bool(s.is_empty()),
+            LiteralValue::Bool(b) => LiteralValue::Bool(!b),
+            LiteralValue::Nil => LiteralValue::Bool(true),
+            // Callables, classes and instances are always truthy
             LiteralValue::Callable {
+                id: _,
                 name: _,
                 arity: _,
+                line: _,
                 fun: _,
-            } => {
-                panic!("Cannot use callable as truthy value")
             }
+            | LiteralValue::Class(_)
+            | LiteralValue::Instance {
+                class: _,
+                fields: _,
+            } => LiteralValue::Bool(false),
         }
     }
 
     // Check is a given TokenType is True
     pub fn is_truthy(&self) -> LiteralValue {
         match self {
-            LiteralValue::Number(e) => {
-                if *e == 0. {
-                    LiteralValue::False
-                } else {
-                    LiteralValue::True
-                }
-            }
-            LiteralValue::StringValue(s) => {
-                if s.is_empty() {
-                    LiteralValue::False
-                } else {
-                    LiteralValue::True
-                }
-            }
-            LiteralValue::True => LiteralValue::True,
-            LiteralValue::False => LiteralValue::False,
-            LiteralValue::Nil => LiteralValue::False,
+            LiteralValue::Number(e) => LiteralValue::Bool(*e != 0.),
+            LiteralValue::StringValue(s) => LiteralValue::Bool(!s.is_empty()),
+            LiteralValue::Bool(b) => LiteralValue::Bool(*b),
+            LiteralValue::Nil => LiteralValue::Bool(false),
+            // Callables, classes and instances are always truthy
             LiteralValue::Callable {
+                id: _,
                 name: _,
                 arity: _,
+                line: _,
                 fun: _,
-            } => {
-                panic!("Cannot use callable as truthy value")
             }
+            | LiteralValue::Class(_)
+            | LiteralValue::Instance {
+                class: _,
+                fields: _,
+            } => LiteralValue::Bool(true),
         }
     }
+}
 
-    // Convert rust bool into LiteralValue bool
-    pub fn from_bool(e: bool) -> Self {
-        if e {
-            LiteralValue::True
-        } else {
-            LiteralValue::False
+// Error message for reading an undefined variable, with a suggestion if one is close
+pub fn not_defined(env: &Environment, name: &str) -> String {
+    match env.suggest(name) {
+        Some(similar) => format!(
+            "Variable '{}' is not defined, did you mean '{}'?",
+            name, similar
+        ),
+        None => format!("Variable '{}' is not defined", name),
+    }
+}
+
+// Get the value of a variable, the first read of a lazy var runs its initializer
+// Lazy vars hold nil until then so only nil values need the check
+pub fn read_var(
+    env: &Rc<RefCell<Environment>>,
+    name: &str,
+    distance: Option<usize>,
+) -> Result<LiteralValue, Box<dyn Error>> {
+    let value = env.borrow().get(name, distance);
+    match value {
+        Some(LiteralValue::Nil) => {
+            let deferred = env.borrow_mut().take_deferred(name, distance);
+            match deferred {
+                Some(thunk) => {
+                    let value = thunk()?;
+                    let type_name = env.borrow().annotation(name, distance);
+                    if let Some(type_name) = type_name {
+                        check_type(name, &type_name, &value)?;
+                    }
+                    env.borrow_mut().assign(name, value.clone(), distance);
+                    Ok(value)
+                }
+                None => Ok(LiteralValue::Nil),
+            }
+        }
+        Some(value) => Ok(value),
+        None => Err(not_defined(&env.borrow(), name).into()),
+    }
+}
+
+// Error if a value does not match the type its variable was declared with
+pub fn check_type(name: &str, type_name: &str, value: &LiteralValue) -> Result<(), Box<dyn Error>> {
+    if value.to_type() != type_name {
+        return Err(format!(
+            "Variable '{}' is declared as {} but got {}",
+            name,
+            type_name,
+            value.to_type()
+        )
+        .into());
+    }
+    Ok(())
+}
+
+// Apply a unary operator to an already evaluvated value
+pub fn apply_unary(operator: &Token, right: &LiteralValue) -> Result<LiteralValue, Box<dyn Error>> {
+    let res = match (right, &operator.token_type) {
+        (LiteralValue::Number(n), TokenType::Minus) => LiteralValue::Number(-n),
+        (any, TokenType::Bang) => any.is_falsy(),
+        _ => {
+            return Err(format!(
+                "{:?} Not not a valid Unary operator on {}",
+                &operator.token_type,
+                right.to_type()
+            )
+            .into())
+        }
+    };
+    Ok(res)
+}
+
+// Apply a postfix operator to an already evaluvated value
+// n! is exact up to 170!, anything bigger is not a finite f64
+pub fn apply_postfix(
+    operator: &Token,
+    operand: &LiteralValue,
+) -> Result<LiteralValue, Box<dyn Error>> {
+    match (operand, &operator.token_type) {
+        (LiteralValue::Number(n), TokenType::Bang) if n.fract() != 0.0 || *n < 0.0 => {
+            Err(format!(
+                "Factorial expected a non-negative integer and got {}",
+                LiteralValue::format_number(*n)
+            )
+            .into())
+        }
+        (LiteralValue::Number(n), TokenType::Bang) if *n > 170.0 => {
+            Err(format!("Factorial of {} is too large", n).into())
+        }
+        (LiteralValue::Number(n), TokenType::Bang) => {
+            Ok(LiteralValue::Number((1..=*n as u64).map(|i| i as f64).product()))
+        }
+        (other, TokenType::Bang) => {
+            Err(format!("Factorial expected a Number and got {}", other.to_type()).into())
+        }
+        _ => Err(format!(
+            "{:?} Not a valid postfix operator on {}",
+            &operator.token_type,
+            operand.to_type()
+        )
+        .into()),
+    }
+}
+
+// Called with the name and arguments before a native runs, an error stops the call
+pub type NativeHook = Rc<dyn Fn(&str, &[LiteralValue]) -> Result<(), Box<dyn Error>>>;
+
+// Called by breakpoint() with the Environment it was called from and the options of the run
+pub type BreakpointHook =
+    Rc<dyn Fn(Rc<RefCell<Environment>>, &Options) -> Result<(), Box<dyn Error>>>;
+
+// Run the hook of the options if there is one, see Interpreter::set_native_hook
+fn check_native_call(
+    options: &Options,
+    name: &str,
+    args: &[LiteralValue],
+) -> Result<(), Box<dyn Error>> {
+    // Not borrowed while the hook runs
+    let hook = options.borrow().native_hook.clone();
+    match hook {
+        Some(hook) => hook(name, args),
+        None => Ok(()),
+    }
+}
+
+// Operands with nil replaced for lenient mode, None if there is no nil or the operator is not
+// arithmetic
+fn coerce_nil(
+    left: &LiteralValue,
+    operator: &Token,
+    right: &LiteralValue,
+) -> Option<(LiteralValue, LiteralValue)> {
+    let arithmetic = matches!(
+        operator.token_type,
+        TokenType::Plus
+            | TokenType::Minus
+            | TokenType::Star
+            | TokenType::StarStar
+            | TokenType::Slash
+            | TokenType::Percent
+    );
+    let has_nil = matches!(left, LiteralValue::Nil) || matches!(right, LiteralValue::Nil);
+    if !arithmetic || !has_nil {
+        return None;
+    }
+    // nil is "" next to a string and 0 everywhere else, "a" - nil still errors
+    let replace = |value: &LiteralValue, other: &LiteralValue| match (value, other) {
+        (LiteralValue::Nil, LiteralValue::StringValue(_)) => LiteralValue::StringValue(Rc::from("")),
+        (LiteralValue::Nil, _) => LiteralValue::Number(0.0),
+        (value, _) => value.clone(),
+    };
+    Some((replace(left, right), replace(right, left)))
+}
+
+// Apply a binary operator to already evaluvated operands
+// The number as an i64 if it is whole and fits in one
+fn as_integer(n: f64) -> Option<i64> {
+    if n.fract() == 0.0 && n >= i64::MIN as f64 && n < i64::MAX as f64 {
+        Some(n as i64)
+    } else {
+        None
+    }
+}
+
+// + - and * on two whole numbers are done as i64 so going past its range is an error instead of
+// silently losing precision
+// None if either side is not a whole number, the float result is used then
+fn checked_integer(a: f64, operator: &Token, b: f64) -> Result<Option<f64>, Box<dyn Error>> {
+    let (a, b) = match (as_integer(a), as_integer(b)) {
+        (Some(a), Some(b)) => (a, b),
+        _ => return Ok(None),
+    };
+    let res = match operator.token_type {
+        TokenType::Plus => a.checked_add(b),
+        TokenType::Minus => a.checked_sub(b),
+        TokenType::Star => a.checked_mul(b),
+        _ => return Ok(None),
+    };
+    match res {
+        Some(res) => Ok(Some(res as f64)),
+        None => Err(format!(
+            "Line {}: integer overflow in '{}'",
+            operator.line_number, operator.lexeme
+        )
+        .into()),
+    }
+}
+
+// With lenient_nil nil stands in for 0 or "" in arithmetic, see Interpreter::set_lenient_nil
+pub fn apply_binary(
+    left: &LiteralValue,
+    operator: &Token,
+    right: &LiteralValue,
+    lenient_nil: bool,
+) -> Result<LiteralValue, Box<dyn Error>> {
+    if lenient_nil {
+        if let Some((left, right)) = coerce_nil(left, operator, right) {
+            return apply_binary(&left, operator, &right, false);
         }
     }
+    // Long match list of all possible(yet) binary operations
+    let res = match (left, right, &operator.token_type) {
+        (LiteralValue::Number(a), LiteralValue::Number(b), TokenType::Greater) => {
+            LiteralValue::Bool(a > b)
+        }
+        (LiteralValue::Number(a), LiteralValue::Number(b), TokenType::GreaterEqual) => {
+            LiteralValue::Bool(a >= b)
+        }
+        (LiteralValue::Number(a), LiteralValue::Number(b), TokenType::Less) => {
+            LiteralValue::Bool(a < b)
+        }
+        (LiteralValue::Number(a), LiteralValue::Number(b), TokenType::LessEqual) => {
+            LiteralValue::Bool(a <= b)
+        }
+        (
+            LiteralValue::StringValue(a),
+            LiteralValue::StringValue(b),
+            TokenType::Greater,
+        ) => LiteralValue::Bool(a > b),
+        (
+            LiteralValue::StringValue(a),
+            LiteralValue::StringValue(b),
+            TokenType::GreaterEqual,
+        ) => LiteralValue::Bool(a >= b),
+        (
+            LiteralValue::StringValue(a),
+            LiteralValue::StringValue(b),
+            TokenType::Less,
+        ) => LiteralValue::Bool(a < b),
+        (
+            LiteralValue::StringValue(a),
+            LiteralValue::StringValue(b),
+            TokenType::LessEqual,
+        ) => LiteralValue::Bool(a <= b),
+
+        (LiteralValue::Number(a), LiteralValue::Number(b), TokenType::Star) => {
+            LiteralValue::Number(checked_integer(*a, operator, *b)?.unwrap_or(a * b))
+        }
+        (LiteralValue::Number(a), LiteralValue::Number(b), TokenType::StarStar) => {
+            LiteralValue::Number(a.powf(*b))
+        }
+        // Dividing by zero is an error instead of giving Infinity or NaN
+        (LiteralValue::Number(_), LiteralValue::Number(b), TokenType::Slash) if *b == 0.0 => {
+            return Err(format!("Line {}: Division by zero", operator.line_number).into())
+        }
+        (LiteralValue::Number(a), LiteralValue::Number(b), TokenType::Slash) => {
+            LiteralValue::Number(a / b)
+        }
+        (LiteralValue::Number(_), LiteralValue::Number(b), TokenType::Percent) if *b == 0.0 => {
+            return Err(format!("Line {}: Modulo by zero", operator.line_number).into())
+        }
+        (LiteralValue::Number(a), LiteralValue::Number(b), TokenType::Percent) => {
+            LiteralValue::Number(a % b)
+        }
+        (LiteralValue::Number(a), LiteralValue::Number(b), TokenType::Minus) => {
+            LiteralValue::Number(checked_integer(*a, operator, *b)?.unwrap_or(a - b))
+        }
+
+        (LiteralValue::Number(a), LiteralValue::Number(b), TokenType::Plus) => {
+            LiteralValue::Number(checked_integer(*a, operator, *b)?.unwrap_or(a + b))
+        }
+        (
+            LiteralValue::StringValue(a),
+            LiteralValue::StringValue(b),
+            TokenType::Plus,
+        ) => LiteralValue::StringValue(Rc::from(format!("{}{}", a, b))),
+
+        (left, right, TokenType::EqualEqual) => LiteralValue::Bool(left == right),
+        (left, right, TokenType::BangEqual) => LiteralValue::Bool(left != right),
+        // Approximately equal, within DEFAULT_EPSILON (1e-9) so (0.1 + 0.2) ~= 0.3
+        (LiteralValue::Number(a), LiteralValue::Number(b), TokenType::TildeEqual) => {
+            LiteralValue::Bool((a - b).abs() <= DEFAULT_EPSILON)
+        }
+        (left, right, TokenType::TildeEqual) => {
+            return Err(format!(
+                "Line {}: '~=' expected (Number, Number) and got ({}, {})",
+                operator.line_number,
+                left.to_type(),
+                right.to_type()
+            )
+            .into())
+        }
+        _ => {
+            return Err(format!(
+                "{} Not implemented on '{}' and '{}'",
+                &operator.token_type,
+                left.to_type(),
+                right.to_type()
+            )
+            .into())
+        }
+    };
+    Ok(res)
 }
 
+// Byte offsets of the first char and one past the last char of the source a node was parsed from
+pub type Span = (usize, usize);
+
 #[derive(Clone)]
 pub enum Expr {
     Binary {
         left: Box<Expr>,
         operator: Token,
         right: Box<Expr>,
+        span: Span,
     },
     Logical {
         left: Box<Expr>,
         operator: Token,
         right: Box<Expr>,
+        span: Span,
     },
     Grouping {
         expression: Box<Expr>,
+        span: Span,
     },
     Literal {
         literal: LiteralValue,
+        span: Span,
     },
     Unary {
         operator: Token,
         right: Box<Expr>,
+        span: Span,
+    },
+    // An operator after its operand, only the factorial '!'
+    Postfix {
+        operator: Token,
+        operand: Box<Expr>,
+        span: Span,
     },
     Variable {
+        id: usize,
         name: Token,
+        span: Span,
     },
     Assign {
+        id: usize,
         name: Token,
         value: Box<Expr>,
+        span: Span,
     },
     #[allow(dead_code)]
     Call {
         callee: Box<Expr>,
         paren: Token,
         args: Vec<Expr>,
+        span: Span,
     },
     #[allow(clippy::vec_box)]
     AnonFunc {
         paren: Token,
         args: Vec<Token>,
         body: Vec<Box<Stmt>>,
+        span: Span,
+    },
+    // A block used as an expression, its value is the trailing expression
+    #[allow(clippy::vec_box)]
+    Block {
+        stmts: Vec<Box<Stmt>>,
+        value: Box<Expr>,
+        span: Span,
+    },
+    // 'cond ? then_branch : else_branch', only the chosen branch is evaluvated
+    Ternary {
+        cond: Box<Expr>,
+        then_branch: Box<Expr>,
+        else_branch: Box<Expr>,
+        span: Span,
+    },
+    // Property access on an instance, 'object.name'
+    Get {
+        object: Box<Expr>,
+        name: Token,
+        span: Span,
+    },
+    Set {
+        object: Box<Expr>,
+        name: Token,
+        value: Box<Expr>,
+        span: Span,
+    },
+    // Resolved like a variable, the id keys its distance in the Locals
+    This {
+        id: usize,
+        keyword: Token,
+        span: Span,
     },
 }
 
@@ -255,31 +727,50 @@ impl Expr {
                 left,
                 operator,
                 right,
+                span: _,
             } => {
                 let left_str = (*left).to_string();
                 let op = operator.lexeme.clone();
                 let right_str = (*right).to_string();
                 format!("({} {} {})", op, left_str, right_str)
             }
-            Expr::Grouping { expression } => {
+            Expr::Grouping {
+                expression,
+                span: _,
+            } => {
                 format!("(group {})", (*expression).to_string())
             }
-            Expr::Literal { literal } => literal.to_string(),
-            Expr::Unary { operator, right } => {
+            Expr::Literal { literal, span: _ } => literal.to_string(),
+            Expr::Unary {
+                operator,
+                right,
+                span: _,
+            } => {
                 let op_str = operator.lexeme.clone();
                 let right_str = (*right).to_string();
                 format!("({} {})", op_str, right_str)
             }
-            Expr::Variable { name } => {
-                format!("(var {:?})", name)
+            Expr::Postfix {
+                operator,
+                operand,
+                span: _,
+            } => format!("({} {})", (*operand).to_string(), operator.lexeme),
+            Expr::Variable { id: _, name, span: _ } => {
+                format!("(var {})", name.lexeme)
             }
-            Expr::Assign { name, value } => {
-                format!("(assign {:?} {:?})", name, value)
+            Expr::Assign {
+                id: _,
+                name,
+                value,
+                span: _,
+            } => {
+                format!("(assign {} {})", name.lexeme, value.to_string())
             }
             Expr::Logical {
                 left,
                 operator,
                 right,
+                span: _,
             } => {
                 format!(
                     "({} {} {})",
@@ -292,6 +783,7 @@ impl Expr {
                 callee,
                 paren: _,
                 args,
+                span: _,
             } => {
                 format!(
                     "<fn {}> {:?}",
@@ -303,9 +795,162 @@ impl Expr {
                 args,
                 body: _,
                 paren: _,
+                span: _,
             } => {
                 format!("anon/{}", args.len())
             }
+            Expr::Block {
+                stmts,
+                value,
+                span: _,
+            } => {
+                format!(
+                    "(block {}{})",
+                    stmts
+                        .iter()
+                        .map(|stmt| format!("{} ", stmt.to_string()))
+                        .collect::<String>(),
+                    value.to_string()
+                )
+            }
+            Expr::Ternary {
+                cond,
+                then_branch,
+                else_branch,
+                span: _,
+            } => format!(
+                "(? {} {} {})",
+                cond.to_string(),
+                then_branch.to_string(),
+                else_branch.to_string()
+            ),
+            Expr::Get {
+                object,
+                name,
+                span: _,
+            } => format!("(get {} {})", object.to_string(), name.lexeme),
+            Expr::Set {
+                object,
+                name,
+                value,
+                span: _,
+            } => format!(
+                "(set {} {} {})",
+                object.to_string(),
+                name.lexeme,
+                value.to_string()
+            ),
+            Expr::This {
+                id: _,
+                keyword: _,
+                span: _,
+            } => "this".to_string(),
+        }
+    }
+
+    // Where in the source the expression was parsed from
+    pub fn span(&self) -> Span {
+        match self {
+            Expr::Binary { span, .. }
+            | Expr::Logical { span, .. }
+            | Expr::Grouping { span, .. }
+            | Expr::Literal { span, .. }
+            | Expr::Unary { span, .. }
+            | Expr::Postfix { span, .. }
+            | Expr::Variable { span, .. }
+            | Expr::Assign { span, .. }
+            | Expr::Call { span, .. }
+            | Expr::AnonFunc { span, .. }
+            | Expr::Block { span, .. }
+            | Expr::Ternary { span, .. }
+            | Expr::Get { span, .. }
+            | Expr::Set { span, .. }
+            | Expr::This { span, .. } => *span,
+        }
+    }
+
+    // Best effort line number of the expression, literals carry no token
+    pub fn line(&self) -> Option<usize> {
+        match self {
+            Expr::Binary {
+                left: _,
+                operator,
+                right: _,
+                span: _,
+            } => Some(operator.line_number),
+            Expr::Logical {
+                left: _,
+                operator,
+                right: _,
+                span: _,
+            } => Some(operator.line_number),
+            Expr::Grouping {
+                expression,
+                span: _,
+            } => expression.line(),
+            Expr::Literal {
+                literal: _,
+                span: _,
+            } => None,
+            Expr::Unary {
+                operator,
+                right: _,
+                span: _,
+            } => Some(operator.line_number),
+            Expr::Postfix {
+                operator,
+                operand: _,
+                span: _,
+            } => Some(operator.line_number),
+            Expr::Variable { id: _, name, span: _ } => Some(name.line_number),
+            Expr::Assign {
+                id: _,
+                name,
+                value: _,
+                span: _,
+            } => Some(name.line_number),
+            Expr::Call {
+                callee: _,
+                paren,
+                args: _,
+                span: _,
+            } => Some(paren.line_number),
+            Expr::AnonFunc {
+                paren,
+                args: _,
+                body: _,
+                span: _,
+            } => Some(paren.line_number),
+            Expr::Block {
+                stmts,
+                value,
+                span: _,
+            } => stmts
+                .first()
+                .and_then(|stmt| stmt.line())
+                .or_else(|| value.line()),
+            Expr::Ternary {
+                cond,
+                then_branch: _,
+                else_branch: _,
+                span: _,
+            } => cond.line(),
+            Expr::Get {
+                object: _,
+                name,
+                span: _,
+            } => Some(name.line_number),
+            Expr::Set {
+                object: _,
+                name,
+                value: _,
+                span: _,
+            } => Some(name.line_number),
+            Expr::This {
+                id: _,
+                keyword,
+                span: _,
+            } => Some(keyword.line_number),
         }
     }
 
@@ -313,19 +958,44 @@ impl Expr {
     pub fn evaluvate(
         &self,
         env: Rc<RefCell<Environment>>,
-        distance: Option<usize>,
+        locals: &Locals,
+        options: &Options,
     ) -> Result<LiteralValue, Box<dyn Error>> {
         // Result is stored in res and returned as Ok(res) at end
         let res = match self {
-            Expr::AnonFunc { paren, args, body } => {
+            // Run the statements in a new scope and then evaluvate the value in it
+            Expr::Block {
+                stmts,
+                value,
+                span: _,
+            } => {
+                let mut block_env =
+                    Interpreter::for_anon(env.clone(), locals.clone(), options.clone());
+                block_env.interpret(stmts.iter().map(|b| b.as_ref()).collect())?;
+                // A return leaves the function the block is in, not just the block
+                if block_env.returned() {
+                    let value = block_env.specials.borrow_mut().remove("return");
+                    return Err(Box::new(Returned(value.unwrap_or(LiteralValue::Nil))));
+                }
+                value.evaluvate(block_env.environments.clone(), locals, options)?
+            }
+            Expr::AnonFunc {
+                paren,
+                args,
+                body,
+                span: _,
+            } => {
                 // Clone all params to prevent lifetime issues
                 let arguments: Vec<Token> = args.iter().map(|t| (*t).clone()).collect();
                 let body: Vec<Box<Stmt>> = body.iter().map(|b| (*b).clone()).collect();
                 let paren_line = paren.line_number;
+                let locals = locals.clone();
+                let options = options.clone();
 
-                let func_impl = move |args: &Vec<LiteralValue>| {
+                let func_impl = move |args: &Vec<LiteralValue>| -> Result<LiteralValue, Box<dyn Error>> {
                     // Get the new Interpreter
-                    let mut anon_env = Interpreter::for_anon(env.clone());
+                    let mut anon_env =
+                        Interpreter::for_anon(env.clone(), locals.clone(), options.clone());
                     // Define all the parameters in the new Interpreter
                     for (i, arg) in args.iter().enumerate() {
                         anon_env
@@ -337,44 +1007,52 @@ impl Expr {
                     #[allow(clippy::all)]
                     for i in 0..(body.len()) {
                         anon_env
-                            .interpret(vec![body[i].as_ref()])
-                            .unwrap_or_else(|_| {
-                                panic!(
-                                    "Evaluvation failed inside anon_func at line {}",
-                                    paren_line.clone()
-                                )
-                            });
+                            .interpret_body(body[i].as_ref())
+                            .map_err(|e| {
+                                format!("Line {}: inside anon_func: {}", paren_line, e)
+                            })?;
                         if let Some(val) = anon_env.specials.borrow_mut().get("return") {
-                            return val.clone();
+                            return Ok(val.clone());
                         }
                     }
-                    LiteralValue::Nil
+                    Ok(LiteralValue::Nil)
                 };
 
                 LiteralValue::Callable {
+                    id: next_callable_id(),
                     name: "anon_function".to_string(),
-                    arity: args.len(),
+                    arity: AritySpec::exact(args.len()),
+                    line: paren.line_number,
                     fun: Rc::from(func_impl),
                 }
             }
             // If its a Variable Expression we try to get it and return its value
-            Expr::Variable { name } => match env.borrow().get(&name.lexeme, distance) {
-                Some(val) => val.clone(),
-                None => return Err(format!("Variable '{}' is not defined", name.lexeme).into()),
-            },
+            // The resolver gave the distance of locals, anything else is a global
+            Expr::Variable { id, name, span: _ } => {
+                let distance = locals.borrow().get(id).copied();
+                let (env, distance) = resolve_scope(*id, &env, distance);
+                read_var(&env, &name.lexeme, distance)?
+            }
             // Function invokation here
             Expr::Call {
                 callee,
                 paren: _,
                 args,
+                span: _,
             } => {
                 // First evaluvate the callee to get the invoking function defination
-                let callable = callee.evaluvate(env.clone(), distance)?;
+                let callable = callee.evaluvate(env.clone(), locals, options)?;
                 match callable {
                     // Check if function defination matchs its invokation
-                    LiteralValue::Callable { name, arity, fun } => {
+                    LiteralValue::Callable {
+                        id,
+                        name,
+                        arity,
+                        line: _,
+                        fun,
+                    } => {
                         // Check ig number of arguments are correct
-                        if args.len() != arity {
+                        if !arity.accepts(args.len()) {
                             return Err(format!(
                                 "Callable '{}' expexted {} arguments and got {} arguments",
                                 name,
@@ -386,18 +1064,173 @@ impl Expr {
                         // Eval the args to literalvalue
                         let mut args_val = vec![];
                         for arg in args {
-                            args_val.push(arg.evaluvate(env.clone(), distance)?)
+                            args_val.push(arg.evaluvate(env.clone(), locals, options)?)
+                        }
+                        if is_native(id, &name) {
+                            check_native_call(options, &name, &args_val)?;
+                            // breakpoint needs the Environment it was called from which a native
+                            // does not get, so the debugger is started from here
+                            if name == "breakpoint" {
+                                let hook = options.borrow().breakpoint_hook.clone();
+                                if let Some(hook) = hook {
+                                    hook(env.clone(), options)?;
+                                }
+                            }
                         }
                         // Call the fun with the args
-                        fun(&args_val)
+                        fun(&args_val)?
+                    }
+                    // Calling a class makes a new instance and runs its init if it has one
+                    LiteralValue::Class(class) => {
+                        let fields = Rc::new(RefCell::new(HashMap::new()));
+                        let instance = LiteralValue::Instance {
+                            class: class.clone(),
+                            fields: fields.clone(),
+                        };
+                        for (name, init) in &class.fields {
+                            let value = init(&instance)?;
+                            fields.borrow_mut().insert(name.clone(), value);
+                        }
+                        let mut args_val = vec![];
+                        for arg in args {
+                            args_val.push(arg.evaluvate(env.clone(), locals, options)?)
+                        }
+                        match class.methods.get("init") {
+                            Some(bind) => {
+                                if let LiteralValue::Callable {
+                                    id: _,
+                                    name: _,
+                                    arity,
+                                    line: _,
+                                    fun,
+                                } = bind(&instance)
+                                {
+                                    if !arity.accepts(args_val.len()) {
+                                        return Err(format!(
+                                            "Class '{}' expexted {} arguments and got {} arguments",
+                                            class.name,
+                                            arity,
+                                            args_val.len()
+                                        )
+                                        .into());
+                                    }
+                                    fun(&args_val)?;
+                                }
+                            }
+                            None => {
+                                if !args_val.is_empty() {
+                                    return Err(format!(
+                                        "Class '{}' expexted 0 arguments and got {} arguments",
+                                        class.name,
+                                        args_val.len()
+                                    )
+                                    .into());
+                                }
+                            }
+                        }
+                        instance
                     }
                     // If we dont get a callable type return error
                     e => return Err(format!("{} is not callable", e.to_type()).into()),
                 }
             }
+            Expr::Ternary {
+                cond,
+                then_branch,
+                else_branch,
+                span: _,
+            } => {
+                let cond = cond.evaluvate(env.clone(), locals, options)?;
+                if cond.is_truthy() == LiteralValue::Bool(true) {
+                    then_branch.evaluvate(env, locals, options)?
+                } else {
+                    else_branch.evaluvate(env, locals, options)?
+                }
+            }
+            // Fields shadow methods of the same name
+            Expr::Get {
+                object,
+                name,
+                span: _,
+            } => match object.evaluvate(env.clone(), locals, options)? {
+                LiteralValue::Instance { class, fields } => {
+                    let field = fields.borrow().get(&name.lexeme).cloned();
+                    match field {
+                        Some(val) => val,
+                        None => {
+                            let instance = LiteralValue::Instance {
+                                class: class.clone(),
+                                fields: fields.clone(),
+                            };
+                            match class.methods.get(&name.lexeme) {
+                                Some(bind) => bind(&instance),
+                                None => {
+                                    return Err(format!(
+                                        "Line {}: Undefined property '{}'",
+                                        name.line_number, name.lexeme
+                                    )
+                                    .into())
+                                }
+                            }
+                        }
+                    }
+                }
+                e => {
+                    return Err(format!(
+                        "Line {}: Only instances have properties, got {}",
+                        name.line_number,
+                        e.to_type()
+                    )
+                    .into())
+                }
+            },
+            Expr::Set {
+                object,
+                name,
+                value,
+                span: _,
+            } => match object.evaluvate(env.clone(), locals, options)? {
+                LiteralValue::Instance { class: _, fields } => {
+                    let value = value.evaluvate(env.clone(), locals, options)?;
+                    fields.borrow_mut().insert(name.lexeme.clone(), value.clone());
+                    value
+                }
+                e => {
+                    return Err(format!(
+                        "Line {}: Only instances have fields, got {}",
+                        name.line_number,
+                        e.to_type()
+                    )
+                    .into())
+                }
+            },
+            Expr::This {
+                id,
+                keyword,
+                span: _,
+            } => {
+                let distance = locals.borrow().get(id).copied();
+                let (env, distance) = resolve_scope(*id, &env, distance);
+                let value = env.borrow().get(&keyword.lexeme, distance);
+                match value {
+                    Some(val) => val,
+                    None => return Err(not_defined(&env.borrow(), &keyword.lexeme).into()),
+                }
+            }
             // Assign a new value to a variable
-            Expr::Assign { name, value } => {
-                let new_value = (*value).evaluvate(env.clone(), distance)?;
+            Expr::Assign {
+                id,
+                name,
+                value,
+                span: _,
+            } => {
+                let new_value = (*value).evaluvate(env.clone(), locals, options)?;
+                let distance = locals.borrow().get(id).copied();
+                let (env, distance) = resolve_scope(*id, &env, distance);
+                let type_name = env.borrow().annotation(&name.lexeme, distance);
+                if let Some(type_name) = type_name {
+                    check_type(&name.lexeme, &type_name, &new_value)?;
+                }
                 let assign_success =
                     env.borrow_mut()
                         .assign(&name.lexeme, new_value.clone(), distance);
@@ -414,115 +1247,55 @@ impl Expr {
                 left,
                 operator,
                 right,
+                span: _,
             } => {
                 // Get the lhs eq
-                let lhs_expr = left.evaluvate(env.clone(), distance)?;
+                let lhs_expr = left.evaluvate(env.clone(), locals, options)?;
 
                 if operator.token_type == TokenType::Or {
                     // If the operator is or and the LHS is true return it and dont compute RHS
-                    if lhs_expr.is_truthy() == LiteralValue::True {
+                    if lhs_expr.is_truthy() == LiteralValue::Bool(true) {
                         return Ok(lhs_expr);
                     }
                 // If operator is AND and LHS is false, Return LHS
-                } else if lhs_expr.is_falsy() == LiteralValue::True {
+                } else if lhs_expr.is_falsy() == LiteralValue::Bool(true) {
                     return Ok(lhs_expr);
                 }
                 // Otherwise return RHS
-                let rhs_expr = right.evaluvate(env.clone(), distance)?;
+                let rhs_expr = right.evaluvate(env.clone(), locals, options)?;
                 return Ok(rhs_expr);
             }
-            Expr::Literal { literal } => literal.clone(),
-            Expr::Grouping { expression } => expression.evaluvate(env, distance)?,
-            Expr::Unary { operator, right } => {
+            Expr::Literal { literal, span: _ } => literal.clone(),
+            Expr::Grouping {
+                expression,
+                span: _,
+            } => expression.evaluvate(env, locals, options)?,
+            Expr::Unary {
+                operator,
+                right,
+                span: _,
+            } => {
                 // Get the RHS
-                let right = &right.evaluvate(env, distance)?;
-                // Match the operation with the evaluvated expression
-                match (right, &operator.token_type) {
-                    (LiteralValue::Number(n), TokenType::Minus) => LiteralValue::Number(-n),
-                    (any, TokenType::Bang) => any.is_falsy(),
-                    _ => {
-                        return Err(format!(
-                            "{:?} Not not a valid Unary operator on {}",
-                            &operator.token_type,
-                            right.to_type()
-                        )
-                        .into())
-                    }
-                }
+                let right = &right.evaluvate(env, locals, options)?;
+                apply_unary(operator, right)?
+            }
+            Expr::Postfix {
+                operator,
+                operand,
+                span: _,
+            } => {
+                let operand = &operand.evaluvate(env, locals, options)?;
+                apply_postfix(operator, operand)?
             }
             Expr::Binary {
                 left,
                 operator,
                 right,
+                span: _,
             } => {
-                let left = &left.evaluvate(env.clone(), distance)?;
-                let right = &right.evaluvate(env.clone(), distance)?;
-                // Long match list of all possible(yet) binary operations
-                match (left, right, &operator.token_type) {
-                    (LiteralValue::Number(a), LiteralValue::Number(b), TokenType::Greater) => {
-                        LiteralValue::from_bool(a > b)
-                    }
-                    (LiteralValue::Number(a), LiteralValue::Number(b), TokenType::GreaterEqual) => {
-                        LiteralValue::from_bool(a >= b)
-                    }
-                    (LiteralValue::Number(a), LiteralValue::Number(b), TokenType::Less) => {
-                        LiteralValue::from_bool(a < b)
-                    }
-                    (LiteralValue::Number(a), LiteralValue::Number(b), TokenType::LessEqual) => {
-                        LiteralValue::from_bool(a <= b)
-                    }
-                    (
-                        LiteralValue::StringValue(a),
-                        LiteralValue::StringValue(b),
-                        TokenType::Greater,
-                    ) => LiteralValue::from_bool(a > b),
-                    (
-                        LiteralValue::StringValue(a),
-                        LiteralValue::StringValue(b),
-                        TokenType::GreaterEqual,
-                    ) => LiteralValue::from_bool(a >= b),
-                    (
-                        LiteralValue::StringValue(a),
-                        LiteralValue::StringValue(b),
-                        TokenType::Less,
-                    ) => LiteralValue::from_bool(a < b),
-                    (
-                        LiteralValue::StringValue(a),
-                        LiteralValue::StringValue(b),
-                        TokenType::LessEqual,
-                    ) => LiteralValue::from_bool(a <= b),
-
-                    (LiteralValue::Number(a), LiteralValue::Number(b), TokenType::Star) => {
-                        LiteralValue::Number(a * b)
-                    }
-                    (LiteralValue::Number(a), LiteralValue::Number(b), TokenType::Slash) => {
-                        LiteralValue::Number(a / b)
-                    }
-                    (LiteralValue::Number(a), LiteralValue::Number(b), TokenType::Minus) => {
-                        LiteralValue::Number(a - b)
-                    }
-
-                    (LiteralValue::Number(a), LiteralValue::Number(b), TokenType::Plus) => {
-                        LiteralValue::Number(a + b)
-                    }
-                    (
-                        LiteralValue::StringValue(a),
-                        LiteralValue::StringValue(b),
-                        TokenType::Plus,
-                    ) => LiteralValue::StringValue(format!("{}{}", a, b)),
-
-                    (left, right, TokenType::EqualEqual) => LiteralValue::from_bool(left == right),
-                    (left, right, TokenType::BangEqual) => LiteralValue::from_bool(left != right),
-                    _ => {
-                        return Err(format!(
-                            "{} Not implemented on '{}' and '{}'",
-                            &operator.token_type,
-                            left.to_type(),
-                            right.to_type()
-                        )
-                        .into())
-                    }
-                }
+                let left = &left.evaluvate(env.clone(), locals, options)?;
+                let right = &right.evaluvate(env.clone(), locals, options)?;
+                apply_binary(left, operator, right, options.borrow().lenient_nil)?
             }
         };
         Ok(res)
@@ -536,7 +1309,7 @@ impl Expr {
 
 #[cfg(test)]
 mod tests {
-    use std::{collections::HashMap, usize};
+    use std::collections::HashMap;
 
     use super::*;
     use crate::scanner::TokenType;
@@ -547,31 +1320,40 @@ mod tests {
             token_type: TokenType::Minus,
             lexeme: "-".to_string(),
             literal: None,
-            line_number: 1 as usize,
+            line_number: 1,
+            offset: 0,
+            length: 1,
         };
 
         let onetwothree = Box::new(Expr::Literal {
             literal: LiteralValue::Number(123.0),
+            span: (0, 0),
         });
         let multi = Token {
             token_type: TokenType::Star,
             lexeme: "*".to_string(),
             literal: None,
-            line_number: 1 as usize,
+            line_number: 1,
+            offset: 0,
+            length: 1,
         };
         let group = Box::new(Expr::Grouping {
             expression: Box::new(Expr::Literal {
                 literal: LiteralValue::Number(45.67),
+                span: (0, 0),
             }),
+            span: (0, 0),
         });
 
         let ast = Expr::Binary {
             left: Box::new(Expr::Unary {
                 operator: minus_token,
                 right: onetwothree,
+                span: (0, 0),
             }),
             operator: multi,
             right: group,
+            span: (0, 0),
         };
 
         ast.print();
@@ -579,6 +1361,231 @@ mod tests {
     }
 
     #[test]
+    fn callable_arity_range() {
+        #[allow(clippy::ptr_arg)]
+        fn count_impl(args: &Vec<LiteralValue>) -> Result<LiteralValue, Box<dyn Error>> {
+            Ok(LiteralValue::Number(args.len() as f64))
+        }
+        let callee = Expr::Literal {
+            literal: LiteralValue::Callable {
+                id: next_callable_id(),
+                name: "count".to_string(),
+                arity: AritySpec::range(1, 3),
+                line: 0,
+                fun: Rc::new(count_impl),
+            },
+            span: (0, 0),
+        };
+        let paren = Token {
+            token_type: TokenType::RightParen,
+            lexeme: ")".to_string(),
+            literal: None,
+            line_number: 1,
+            offset: 0,
+            length: 1,
+        };
+        let call = |n: usize| Expr::Call {
+            callee: Box::new(callee.clone()),
+            paren: paren.clone(),
+            args: vec![
+                Expr::Literal {
+                    literal: LiteralValue::Nil,
+                    span: (0, 0),
+                };
+                n
+            ],
+            span: (0, 0),
+        };
+        let env = Rc::new(RefCell::new(Environment::new()));
+        let (locals, options) = (Locals::default(), Options::default());
+
+        assert!(call(0).evaluvate(env.clone(), &locals, &options).is_err());
+        assert_eq!(
+            call(1).evaluvate(env.clone(), &locals, &options).unwrap(),
+            LiteralValue::Number(1.0)
+        );
+        assert_eq!(
+            call(3).evaluvate(env.clone(), &locals, &options).unwrap(),
+            LiteralValue::Number(3.0)
+        );
+        let err = call(4).evaluvate(env.clone(), &locals, &options).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Callable 'count' expexted 1..3 arguments and got 4 arguments"
+        );
+    }
+
+    #[test]
+    fn lenient_nil_arithmetic() {
+        let token = |token_type, lexeme: &str| Token {
+            token_type,
+            lexeme: lexeme.to_string(),
+            literal: None,
+            line_number: 1,
+            offset: 0,
+            length: lexeme.len(),
+        };
+        let plus = token(TokenType::Plus, "+");
+        let one = LiteralValue::Number(1.0);
+        let string = LiteralValue::StringValue(Rc::from("a"));
+
+        assert!(apply_binary(&LiteralValue::Nil, &plus, &one, false).is_err());
+        assert_eq!(apply_binary(&LiteralValue::Nil, &plus, &one, true).unwrap(), one);
+        assert_eq!(apply_binary(&string, &plus, &LiteralValue::Nil, true).unwrap(), string);
+        assert_eq!(
+            apply_binary(&one, &token(TokenType::Star, "*"), &LiteralValue::Nil, true).unwrap(),
+            LiteralValue::Number(0.0)
+        );
+        // Comparisons are left alone
+        let equal = token(TokenType::EqualEqual, "==");
+        assert_eq!(
+            apply_binary(&LiteralValue::Nil, &equal, &LiteralValue::Nil, true).unwrap(),
+            LiteralValue::Bool(true)
+        );
+    }
+
+    #[test]
+    fn integer_overflow() {
+        let star = Token {
+            token_type: TokenType::Star,
+            lexeme: "*".to_string(),
+            literal: None,
+            line_number: 3,
+            offset: 0,
+            length: 1,
+        };
+        let big = LiteralValue::Number(4_000_000_000_000.0);
+
+        let err = apply_binary(&big, &star, &big, false).unwrap_err();
+        assert_eq!(err.to_string(), "Line 3: integer overflow in '*'");
+        assert_eq!(
+            apply_binary(&big, &star, &LiteralValue::Number(2.0), false).unwrap(),
+            LiteralValue::Number(8_000_000_000_000.0)
+        );
+        // Fractions stay floats
+        assert_eq!(
+            apply_binary(&LiteralValue::Number(0.5), &star, &LiteralValue::Number(1e300), false)
+                .unwrap(),
+            LiteralValue::Number(5e299)
+        );
+    }
+
+    #[test]
+    fn non_finite_numbers_print_as_words() {
+        // Division by zero errors but Infinity can still come from overflowing floats
+        assert_eq!(LiteralValue::Number(f64::MAX * 2.0).to_string(), "Infinity");
+        assert_eq!(LiteralValue::Number(f64::MIN * 2.0).to_string(), "-Infinity");
+        assert_eq!(LiteralValue::Number(f64::NAN).to_string(), "NaN");
+    }
+
+    #[test]
+    fn numbers_format_without_trailing_zero() {
+        assert_eq!(LiteralValue::Number(1.0).to_string(), "1");
+        assert_eq!(LiteralValue::Number(1.5).to_string(), "1.5");
+        assert_eq!(LiteralValue::Number(-0.0).to_string(), "0");
+        assert_eq!(LiteralValue::Number(1.0 / 0.0).to_string(), "Infinity");
+        assert_eq!(LiteralValue::Number(10.0 / 4.0 * 2.0).to_string(), "5");
+        assert_eq!(LiteralValue::Number(1e21).to_string(), "1000000000000000000000");
+    }
+
+    #[test]
+    fn display_string_drops_quotes() {
+        let string = LiteralValue::StringValue(Rc::from("hello"));
+        assert_eq!(string.display_string(), "hello");
+        assert_eq!(string.to_string(), "\"hello\"");
+        assert_eq!(LiteralValue::Number(1.5).display_string(), "1.5");
+    }
+
+    #[test]
+    fn inspect_shows_type() {
+        assert_eq!(LiteralValue::Number(1.0).inspect(), "1 : Number");
+        assert_eq!(LiteralValue::StringValue(Rc::from("1")).inspect(), "\"1\" : String");
+        assert_eq!(LiteralValue::Nil.inspect(), "nil : Nil");
+    }
+
+    #[test]
+    fn string_clone_is_shared() {
+        #[allow(clippy::ptr_arg)]
+        fn identity_impl(args: &Vec<LiteralValue>) -> Result<LiteralValue, Box<dyn Error>> {
+            Ok(args[0].clone())
+        }
+        let identity = Expr::Literal {
+            literal: LiteralValue::Callable {
+                id: next_callable_id(),
+                name: "identity".to_string(),
+                arity: AritySpec::exact(1),
+                line: 0,
+                fun: Rc::new(identity_impl),
+            },
+            span: (0, 0),
+        };
+        let paren = Token {
+            token_type: TokenType::RightParen,
+            lexeme: ")".to_string(),
+            literal: None,
+            line_number: 1,
+            offset: 0,
+            length: 1,
+        };
+        let big: Rc<str> = Rc::from("x".repeat(1 << 20));
+        let env = Rc::new(RefCell::new(Environment::new()));
+        let (locals, options) = (Locals::default(), Options::default());
+
+        // Pass the string through a few calls, every hop should reuse the same allocation
+        let mut value = LiteralValue::StringValue(big.clone());
+        for _ in 0..10 {
+            let call = Expr::Call {
+                callee: Box::new(identity.clone()),
+                paren: paren.clone(),
+                args: vec![Expr::Literal {
+                    literal: value,
+                    span: (0, 0),
+                }],
+                span: (0, 0),
+            };
+            value = call.evaluvate(env.clone(), &locals, &options).unwrap();
+        }
+
+        match value {
+            LiteralValue::StringValue(s) => assert!(Rc::ptr_eq(&s, &big)),
+            _ => panic!("Expected a string"),
+        }
+
+        // Concatenation makes a fresh string and leaves the operands alone
+        let plus = Token {
+            token_type: TokenType::Plus,
+            lexeme: "+".to_string(),
+            literal: None,
+            line_number: 1,
+            offset: 0,
+            length: 1,
+        };
+        let concat = Expr::Binary {
+            left: Box::new(Expr::Literal {
+                literal: LiteralValue::StringValue(big.clone()),
+                span: (0, 0),
+            }),
+            operator: plus,
+            right: Box::new(Expr::Literal {
+                literal: LiteralValue::StringValue(Rc::from("y")),
+                span: (0, 0),
+            }),
+            span: (0, 0),
+        };
+        match concat.evaluvate(env, &locals, &options).unwrap() {
+            LiteralValue::StringValue(s) => {
+                assert!(!Rc::ptr_eq(&s, &big));
+                assert_eq!(s.len(), big.len() + 1);
+                assert!(s.ends_with('y'));
+            }
+            _ => panic!("Expected a string"),
+        }
+        assert_eq!(big.len(), 1 << 20);
+    }
+
+    // Expr hashes by address so the fields of instances it may hold do not matter
+    #[test]
+    #[allow(clippy::mutable_key_type)]
     fn expr_traits() {
         let mut hm = HashMap::new();
 
@@ -586,31 +1593,40 @@ mod tests {
             token_type: TokenType::Minus,
             lexeme: "-".to_string(),
             literal: None,
-            line_number: 1 as usize,
+            line_number: 1,
+            offset: 0,
+            length: 1,
         };
 
         let onetwothree = Box::new(Expr::Literal {
             literal: LiteralValue::Number(123.0),
+            span: (0, 0),
         });
         let multi = Token {
             token_type: TokenType::Star,
             lexeme: "*".to_string(),
             literal: None,
-            line_number: 1 as usize,
+            line_number: 1,
+            offset: 0,
+            length: 1,
         };
         let group = Box::new(Expr::Grouping {
             expression: Box::new(Expr::Literal {
                 literal: LiteralValue::Number(45.67),
+                span: (0, 0),
             }),
+            span: (0, 0),
         });
 
         let ast = Expr::Binary {
             left: Box::new(Expr::Unary {
                 operator: minus_token,
                 right: onetwothree,
+                span: (0, 0),
             }),
             operator: multi,
             right: group,
+            span: (0, 0),
         };
 
         let ast = std::rc::Rc::new(ast);
@@ -624,31 +1640,40 @@ mod tests {
             token_type: TokenType::Minus,
             lexeme: "-".to_string(),
             literal: None,
-            line_number: 1 as usize,
+            line_number: 1,
+            offset: 0,
+            length: 1,
         };
 
         let onetwothree = Box::new(Expr::Literal {
             literal: LiteralValue::Number(123.0),
+            span: (0, 0),
         });
         let multi = Token {
             token_type: TokenType::Star,
             lexeme: "*".to_string(),
             literal: None,
-            line_number: 1 as usize,
+            line_number: 1,
+            offset: 0,
+            length: 1,
         };
         let group = Box::new(Expr::Grouping {
             expression: Box::new(Expr::Literal {
                 literal: LiteralValue::Number(45.67),
+                span: (0, 0),
             }),
+            span: (0, 0),
         });
 
         let ast = Expr::Binary {
             left: Box::new(Expr::Unary {
                 operator: minus_token,
                 right: onetwothree,
+                span: (0, 0),
             }),
             operator: multi,
             right: group,
+            span: (0, 0),
         };
 
         let ast = std::rc::Rc::new(ast);
@@ -1,17 +1,151 @@
-use crate::expr::Expr;
-use crate::Token;
-use crate::{environments::Environment, expr::LiteralValue, stmt::Stmt};
+use crate::expr::{
+    check_type, next_callable_id, AritySpec, BreakpointHook, ClassDef, Expr, FieldInit,
+    MethodBinder, NativeHook,
+};
+use crate::output;
+use crate::vm::{self, Compiler};
+use crate::{Token, TokenType};
+use crate::environments::{memoized, resolve_scope, Environment, Thunk};
+use crate::{expr::LiteralValue, stmt::Stmt};
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::error::Error;
 use std::rc::Rc;
 
+// Distance to the scope each resolved Variable and Assign is defined in, keyed by their id
+// Shared by every Interpreter of a run, the ids of globals are not in it
+pub type Locals = Rc<RefCell<HashMap<usize, usize>>>;
+
+// Settings that change how expressions are evaluvated
+// Shared like Locals by every Interpreter of a run, Interpreter::new makes a fresh one
+#[derive(Default)]
+pub struct RunOptions {
+    // nil stands in for 0, or "" next to a string, in + - * and /
+    pub lenient_nil: bool,
+    // Called before every native call, see Interpreter::set_native_hook
+    pub native_hook: Option<NativeHook>,
+    // Started by breakpoint(), without one breakpoint() does nothing
+    pub breakpoint_hook: Option<BreakpointHook>,
+}
+
+pub type Options = Rc<RefCell<RunOptions>>;
+
+// A return inside a block expression, carried up as an error to the function it leaves
+// The function body catches it in Interpreter::interpret_body
+pub struct Returned(pub LiteralValue);
+
+impl std::fmt::Debug for Returned {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Returned({})", self.0.to_string())
+    }
+}
+
+impl std::fmt::Display for Returned {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Cannot return from top-level code")
+    }
+}
+
+impl Error for Returned {}
+
+// One side of a NumericCond, variables keep the Environment they are looked up in
+enum Operand {
+    Var(String, Rc<RefCell<Environment>>, Option<usize>),
+    Number(f64),
+}
+
+impl Operand {
+    fn new(expr: &Expr, env: &Rc<RefCell<Environment>>, locals: &Locals) -> Option<Self> {
+        match expr {
+            Expr::Literal {
+                literal: LiteralValue::Number(n),
+                span: _,
+            } => Some(Operand::Number(*n)),
+            Expr::Variable { id, name, span: _ } => {
+                let distance = locals.borrow().get(id).copied();
+                let (env, distance) = resolve_scope(*id, env, distance);
+                Some(Operand::Var(name.lexeme.clone(), env, distance))
+            }
+            _ => None,
+        }
+    }
+
+    fn value(&self) -> Option<f64> {
+        match self {
+            Operand::Var(name, env, distance) => match env.borrow().get(name, *distance) {
+                Some(LiteralValue::Number(n)) => Some(n),
+                _ => None,
+            },
+            Operand::Number(n) => Some(*n),
+        }
+    }
+}
+
+// Loop condition like `i < n` comparing variables and number literals
+// Checked without walking the Expr, anything it can not handle gives None and the Expr is
+// evaluvated as usual
+struct NumericCond {
+    left: Operand,
+    operator: TokenType,
+    right: Operand,
+}
+
+impl NumericCond {
+    fn new(cond: &Expr, env: &Rc<RefCell<Environment>>, locals: &Locals) -> Option<Self> {
+        match cond {
+            Expr::Binary {
+                left,
+                operator,
+                right,
+                span: _,
+            } => match operator.token_type {
+                TokenType::Less
+                | TokenType::LessEqual
+                | TokenType::Greater
+                | TokenType::GreaterEqual
+                | TokenType::EqualEqual
+                | TokenType::BangEqual => Some(NumericCond {
+                    left: Operand::new(left, env, locals)?,
+                    operator: operator.token_type.clone(),
+                    right: Operand::new(right, env, locals)?,
+                }),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn check(&self) -> Option<bool> {
+        let (a, b) = (self.left.value()?, self.right.value()?);
+        match self.operator {
+            TokenType::Less => Some(a < b),
+            TokenType::LessEqual => Some(a <= b),
+            TokenType::Greater => Some(a > b),
+            TokenType::GreaterEqual => Some(a >= b),
+            TokenType::EqualEqual => Some(a == b),
+            TokenType::BangEqual => Some(a != b),
+            _ => None,
+        }
+    }
+}
+
 // Main heart of the operation
 pub struct Interpreter {
     pub specials: Rc<RefCell<HashMap<String, LiteralValue>>>,
     pub environments: Rc<RefCell<Environment>>,
     // globals: HashMap<String, LiteralValue>,
-    pub locals: Rc<RefCell<HashMap<usize, usize>>>,
+    pub locals: Locals,
+    pub options: Options,
+    // Print every statement before it is executed
+    pub trace: bool,
+    // Run while loops on the compiled stack machine when they can be compiled
+    pub vm: bool,
+    // Parse 'print' as a call to the print native instead of a statement
+    pub print_function: bool,
+    // Id of the named function whose body this Interpreter runs
+    function: Option<usize>,
+    // Arguments of a tail call to that function, it runs its body again with them
+    tail_call: Option<Vec<LiteralValue>>,
 }
 
 impl Interpreter {
@@ -22,42 +156,106 @@ impl Interpreter {
             environments: Rc::new(RefCell::new(Environment::new())),
             // globals: Interpreter::get_globals(),
             locals: Rc::new(RefCell::new(HashMap::new())),
+            options: Options::default(),
+            trace: false,
+            vm: false,
+            print_function: false,
+            function: None,
+            tail_call: None,
         }
     }
 
+    // Let nil stand in for 0, or "" next to a string, in + - * and /
+    pub fn set_lenient_nil(&mut self, lenient: bool) {
+        self.options.borrow_mut().lenient_nil = lenient;
+    }
+
+    // Run hook before every native call, it can refuse the call by returning an error
+    // None removes it
+    #[allow(dead_code)]
+    pub fn set_native_hook(&mut self, hook: Option<NativeHook>) {
+        self.options.borrow_mut().native_hook = hook;
+    }
+
+    // Run hook when breakpoint() is called, the REPL sets one that opens a nested prompt
+    pub fn set_breakpoint_hook(&mut self, hook: Option<BreakpointHook>) {
+        self.options.borrow_mut().breakpoint_hook = hook;
+    }
+
+    // Forget everything a previous run defined, the natives are defined again
+    // trace, vm, print_function and the options are kept as they are settings and not state
+    pub fn reset(&mut self) {
+        self.specials.borrow_mut().clear();
+        self.environments = Rc::new(RefCell::new(Environment::new()));
+        self.locals.borrow_mut().clear();
+    }
+
     // Return a new Interpreter with a enclosing parent of another Interpreter
-    fn for_closure(parent: Rc<RefCell<Environment>>) -> Self {
-        let environments = Rc::new(RefCell::new(Environment::new()));
-        environments.borrow_mut().enclosing = Some(parent);
+    fn for_closure(parent: Rc<RefCell<Environment>>, locals: Locals, options: Options) -> Self {
+        let environments = Rc::new(RefCell::new(Environment::new_enclosed(parent)));
         Interpreter {
             specials: Rc::new(RefCell::new(HashMap::new())),
             environments,
             // globals: Interpreter::get_globals(),
-            locals: Rc::new(RefCell::new(HashMap::new())),
+            locals,
+            options,
+            trace: false,
+            vm: false,
+            print_function: false,
+            function: None,
+            tail_call: None,
         }
     }
 
-    pub fn for_anon(parent: Rc<RefCell<Environment>>) -> Self {
-        let environments = Rc::new(RefCell::new(Environment::new()));
-        environments.borrow_mut().enclosing = Some(parent);
+    pub fn for_anon(parent: Rc<RefCell<Environment>>, locals: Locals, options: Options) -> Self {
+        let environments = Rc::new(RefCell::new(Environment::new_enclosed(parent)));
         Interpreter {
             specials: Rc::new(RefCell::new(HashMap::new())),
             environments,
             // globals: Interpreter::get_globals(),
-            locals: Rc::new(RefCell::new(HashMap::new())),
+            locals,
+            options,
+            trace: false,
+            vm: false,
+            print_function: false,
+            function: None,
+            tail_call: None,
         }
     }
 
+    // Returns the value of the last statement if it was an expression statement
     #[allow(clippy::let_and_return)]
     pub fn interpret(&mut self, stmts: Vec<&Stmt>) -> Result<Option<LiteralValue>, Box<dyn Error>> {
+        let mut last = None;
         for stmt in stmts {
+            // A return anywhere inside the function skips the rest of it
+            if self.returned() {
+                break;
+            }
+            last = None;
+            if self.trace {
+                output::flush()?;
+                match stmt.line() {
+                    Some(line) => eprintln!("[line {}] {}", line, stmt.to_string()),
+                    None => eprintln!("[line ?] {}", stmt.to_string()),
+                }
+            }
             match stmt {
-                Stmt::Return { keyword: _, value } => {
+                // A self call in tail position is not made here, see tail_call_args
+                Stmt::Return {
+                    keyword: _,
+                    value,
+                    span: _,
+                } => {
+                    if let Some(args) = self.tail_call_args(value)? {
+                        self.tail_call = Some(args);
+                        self.specials
+                            .borrow_mut()
+                            .insert("return".to_string(), LiteralValue::Nil);
+                        continue;
+                    }
                     let value = match value {
-                        Some(expr) => {
-                            let distance = self.get_distance(expr);
-                            expr.evaluvate(self.environments.clone(), distance)?
-                        }
+                        Some(expr) => self.evaluvate(expr)?,
                         None => LiteralValue::Nil,
                     };
 
@@ -66,91 +264,222 @@ impl Interpreter {
                         .insert("return".to_string(), value);
                 }
                 // Mother of hell ah function
-                Stmt::Function { name, params, body } => {
-                    // Get the arity
-                    let arity = params.len();
-
-                    // Clone all params to prevent lifetime issues
-                    let params: Vec<Token> = params.iter().map(|t| (*t).clone()).collect();
-                    let body: Vec<Box<Stmt>> = body.iter().map(|b| (*b).clone()).collect();
-                    let name_clone = name.lexeme.clone();
-
-                    let parent_env = self.environments.clone();
-                    // Make a function implementaion
-                    let func_impl = move |args: &Vec<LiteralValue>| {
-                        // Get the new Interpreter
-                        let mut closure_interpreter = Interpreter::for_closure(parent_env.clone());
-                        // Define all the parameters in the new Interpreter
-                        for (i, arg) in args.iter().enumerate() {
-                            closure_interpreter.environments.borrow_mut().define(
-                                params[i].lexeme.clone(),
-                                arg.clone(),
+                Stmt::Function {
+                    name,
+                    params,
+                    body,
+                    pure,
+                    span: _,
+                } => {
+                    let callable = self.function_value(name, params, body, *pure);
+
+                    // Initialize the Callable in the Environment(parent Interpreter here)
+                    // func_impl holds on to this same Environment so the body sees the name for
+                    // recursion, at the top level it is a global like the resolver expects
+                    let scope = self.environments.borrow().scope_distance();
+                    self.environments
+                        .borrow_mut()
+                        .define(name.lexeme.clone(), callable, scope);
+                }
+                // Methods are bound when they are looked up on an instance, each binding gets
+                // a scope holding 'this' between the method and where the class was declared
+                Stmt::Class {
+                    name,
+                    fields,
+                    methods,
+                    span: _,
+                } => {
+                    // Field defaults are evaluvated in the same kind of scope as methods run in
+                    let mut inits: Vec<(String, FieldInit)> = vec![];
+                    for (field, value) in fields {
+                        let class_env = self.environments.clone();
+                        let locals = self.locals.clone();
+                        let options = self.options.clone();
+                        let value = value.clone();
+                        let init = move |instance: &LiteralValue| {
+                            let field_env = Interpreter::for_closure(
+                                class_env.clone(),
+                                locals.clone(),
+                                options.clone(),
+                            );
+                            field_env.environments.borrow_mut().define(
+                                "this".to_string(),
+                                instance.clone(),
                                 Some(0),
                             );
+                            value.evaluvate(field_env.environments.clone(), &locals, &options)
+                        };
+                        inits.push((field.lexeme.clone(), Rc::new(init)));
+                    }
+                    let mut binders: HashMap<String, MethodBinder> = HashMap::new();
+                    for method in methods {
+                        if let Stmt::Function {
+                            name: method_name,
+                            params,
+                            body,
+                            pure,
+                            span: _,
+                        } = method
+                        {
+                            let class_env = self.environments.clone();
+                            let locals = self.locals.clone();
+                            let options = self.options.clone();
+                            let trace = self.trace;
+                            let use_vm = self.vm;
+                            let key = method_name.lexeme.clone();
+                            let method_name = method_name.clone();
+                            let params = params.clone();
+                            let body = body.clone();
+                            let pure = *pure;
+                            let bind = move |instance: &LiteralValue| -> LiteralValue {
+                                let mut method_env = Interpreter::for_closure(
+                                    class_env.clone(),
+                                    locals.clone(),
+                                    options.clone(),
+                                );
+                                method_env.trace = trace;
+                                method_env.vm = use_vm;
+                                method_env.environments.borrow_mut().define(
+                                    "this".to_string(),
+                                    instance.clone(),
+                                    Some(0),
+                                );
+                                method_env.function_value(&method_name, &params, &body, pure)
+                            };
+                            binders.insert(key, Rc::new(bind));
                         }
-                        // Resolve the n-1 line in the body
-                        #[allow(clippy::all)]
-                        for i in 0..(body.len()) {
-                            closure_interpreter
-                                .interpret(vec![body[i].as_ref()])
-                                .unwrap_or_else(|_| {
-                                    panic!("Evaluvation failed inside {:?}", name_clone)
-                                });
-                            if let Some(val) = closure_interpreter.specials.borrow().get("return") {
-                                return val.clone();
-                            }
-                        }
-                        LiteralValue::Nil
-                    };
-                    // Create a Callable
-                    let callable = LiteralValue::Callable {
-                        //name: name.lexeme.clone(),
-                        name: name.to_string(),
-                        arity,
-                        fun: Rc::from(func_impl),
-                    };
-
-                    // Initialize the Callable in the Environment(parent Interpreter here)
+                    }
+                    let class = LiteralValue::Class(Rc::new(ClassDef {
+                        name: name.lexeme.clone(),
+                        fields: inits,
+                        methods: binders,
+                    }));
+                    let scope = self.environments.borrow().scope_distance();
                     self.environments
                         .borrow_mut()
-                        .define(name.lexeme.clone(), callable, Some(0));
+                        .define(name.lexeme.clone(), class, scope);
                 }
                 // Keep executing a Block till the time the flag is true
-                Stmt::WhileLoop { cond, body } => {
-                    let distance = self.get_distance(cond);
-                    let mut flag = cond.evaluvate(self.environments.clone(), distance)?;
-                    while flag.is_truthy() == LiteralValue::True {
+                // If the body never ran execute the else_branch
+                // With --vm the loop is compiled first and only tree-walked if that fails
+                Stmt::WhileLoop {
+                    cond,
+                    body,
+                    else_branch,
+                    span: _,
+                } => {
+                    if self.vm && !self.trace {
+                        if let Some(code) = Compiler::new(self).compile(stmt) {
+                            let lenient_nil = self.options.borrow().lenient_nil;
+                            vm::run(&code, self.environments.clone(), lenient_nil)?;
+                            continue;
+                        }
+                    }
+                    let fast_cond = NumericCond::new(cond, &self.environments, &self.locals);
+                    let mut flag = self.evaluvate(cond)?;
+                    let mut executed = false;
+                    while flag.is_truthy() == LiteralValue::Bool(true) {
+                        executed = true;
                         self.interpret(vec![body.as_ref()])?;
-                        flag = cond.evaluvate(self.environments.clone(), distance)?;
+                        if self.returned() {
+                            break;
+                        }
+                        flag = match fast_cond.as_ref().and_then(|fast| fast.check()) {
+                            Some(flag) => LiteralValue::Bool(flag),
+                            None => self.evaluvate(cond)?,
+                        };
+                    }
+                    if let (false, Some(stmt)) = (executed, else_branch) {
+                        self.interpret(vec![stmt.as_ref()])?;
                     }
                 }
                 // Execute a expresssion regularly
-                Stmt::Expression { expression } => {
-                    let distance = self.get_distance(expression);
-                    expression.evaluvate(self.environments.clone(), distance)?;
+                Stmt::Expression {
+                    expression,
+                    span: _,
+                } => {
+                    last = Some(self.evaluvate(expression)?);
                 }
                 // Evaluvate the value and then print it out
-                Stmt::Print { expression } => {
-                    let distance = self.get_distance(expression);
-                    let val = expression.evaluvate(self.environments.clone(), distance)?;
+                Stmt::Print {
+                    expression,
+                    span: _,
+                } => {
+                    let val = self.evaluvate(expression)?;
 
-                    println!("{}", val.to_string());
+                    output::write_line(&val.display_string())?;
                 }
                 // For a variable resolve its value and then define it in the Environment
-                Stmt::Var { name, initializer } => {
-                    let distance = self.get_distance(initializer);
-                    let val = initializer.evaluvate(self.environments.clone(), distance)?;
+                // A function stored in a variable can call itself so the name is defined before
+                // the closure is created
+                // A type annotation is checked here and remembered for later assignments
+                Stmt::Var {
+                    name,
+                    initializer,
+                    type_name,
+                    lazy: true,
+                    span: _,
+                } => {
+                    // Defined as nil now, read_var swaps in the value on the first read
+                    let scope = self.environments.borrow().scope_distance();
+                    self.environments.borrow_mut().define(
+                        name.lexeme.clone(),
+                        LiteralValue::Nil,
+                        scope,
+                    );
+                    if let Some(type_name) = type_name {
+                        self.environments.borrow_mut().annotate(
+                            &name.lexeme,
+                            &type_name.lexeme,
+                            scope,
+                        );
+                    }
+                    let initializer = initializer.clone();
+                    let env = self.environments.clone();
+                    let locals = self.locals.clone();
+                    let options = self.options.clone();
+                    let thunk: Thunk =
+                        Rc::new(move || initializer.evaluvate(env.clone(), &locals, &options));
+                    self.environments
+                        .borrow_mut()
+                        .defer(&name.lexeme, thunk, scope);
+                }
+                Stmt::Var {
+                    name,
+                    initializer,
+                    type_name,
+                    lazy: false,
+                    span: _,
+                } => {
+                    let scope = self.environments.borrow().scope_distance();
+                    if let Expr::AnonFunc { .. } = initializer {
+                        self.environments.borrow_mut().define(
+                            name.lexeme.clone(),
+                            LiteralValue::Nil,
+                            scope,
+                        );
+                    }
+                    let val = self.evaluvate(initializer)?;
+                    if let Some(type_name) = type_name {
+                        check_type(&name.lexeme, &type_name.lexeme, &val)?;
+                    }
 
                     self.environments
                         .borrow_mut()
-                        .define(name.lexeme.clone(), val, distance);
+                        .define(name.lexeme.clone(), val, scope);
+                    if let Some(type_name) = type_name {
+                        self.environments.borrow_mut().annotate(
+                            &name.lexeme,
+                            &type_name.lexeme,
+                            scope,
+                        );
+                    }
                 }
                 // Make a new Environment, make it the main Environment and make the enclsing the
                 // orignal Environment to run the block
                 // Restore the old Environment when finished with the block
-                Stmt::Block { stmts } => {
-                    let mut new_env = Environment::new();
-                    new_env.enclosing = Some(self.environments.clone());
+                Stmt::Block { stmts, span: _ } => {
+                    let new_env = Environment::new_enclosed(self.environments.clone());
 
                     let old_env = self.environments.clone();
                     self.environments = Rc::new(RefCell::new(new_env));
@@ -165,10 +494,10 @@ impl Interpreter {
                     predicate,
                     then_branch,
                     else_branch,
+                    span: _,
                 } => {
-                    let distance = self.get_distance(predicate);
-                    let truth_val = predicate.evaluvate(self.environments.clone(), distance)?;
-                    if truth_val.is_truthy() == LiteralValue::True {
+                    let truth_val = self.evaluvate(predicate)?;
+                    if truth_val.is_truthy() == LiteralValue::Bool(true) {
                         self.interpret(vec![then_branch.as_ref()])?;
                     } else if let Some(stmt) = else_branch {
                         self.interpret(vec![stmt.as_ref()])?;
@@ -176,17 +505,320 @@ impl Interpreter {
                 }
             };
         }
-        Ok(None)
+        Ok(last)
+    }
+
+    // The arguments of `return f(...)` when f is the function this Interpreter is running
+    // None for any other return, then the value is evaluvated as usual
+    fn tail_call_args(
+        &self,
+        value: &Option<Expr>,
+    ) -> Result<Option<Vec<LiteralValue>>, Box<dyn Error>> {
+        let (Some(function), Some(expr)) = (self.function, value) else {
+            return Ok(None);
+        };
+        let Expr::Call {
+            callee,
+            paren: _,
+            args,
+            span: _,
+        } = expr
+        else {
+            return Ok(None);
+        };
+        if !matches!(callee.as_ref(), Expr::Variable { .. }) {
+            return Ok(None);
+        }
+        match self.evaluvate(callee)? {
+            LiteralValue::Callable {
+                id,
+                name: _,
+                arity,
+                line: _,
+                fun: _,
+            } if id == function && arity.accepts(args.len()) => {
+                let mut args_val = vec![];
+                for arg in args {
+                    args_val.push(self.evaluvate(arg)?);
+                }
+                Ok(Some(args_val))
+            }
+            _ => Ok(None),
+        }
     }
 
-    pub fn resolve(&mut self, expr: &Expr, size: usize) -> Result<(), Box<dyn Error>> {
-        let addr = std::ptr::addr_of!(expr) as usize;
-        self.locals.borrow_mut().insert(addr, size);
+    // Make the Callable for a function declared with self.environments as its closure
+    fn function_value(
+        &self,
+        name: &Token,
+        params: &[Token],
+        body: &[Box<Stmt>],
+        pure: bool,
+    ) -> LiteralValue {
+        // Get the arity
+        let arity = params.len();
+
+        // Clone all params to prevent lifetime issues
+        let params: Vec<Token> = params.to_vec();
+        let body: Vec<Box<Stmt>> = body.iter().map(|b| (*b).clone()).collect();
+        let name_clone = name.lexeme.clone();
+
+        let parent_env = self.environments.clone();
+        let locals = self.locals.clone();
+        let options = self.options.clone();
+        let trace = self.trace;
+        let use_vm = self.vm;
+        let id = next_callable_id();
+        // Make a function implementaion
+        // A tail call to itself gives back new arguments and the body is run again
+        // with them so deep tail recursion does not grow the stack
+        let func_impl = move |args: &Vec<LiteralValue>| -> Result<LiteralValue, Box<dyn Error>> {
+            let mut args = args.clone();
+            loop {
+                // Get the new Interpreter
+                let mut closure_interpreter =
+                    Interpreter::for_closure(parent_env.clone(), locals.clone(), options.clone());
+                closure_interpreter.trace = trace;
+                closure_interpreter.vm = use_vm;
+                closure_interpreter.function = Some(id);
+                // Define all the parameters in the new Interpreter
+                for (i, arg) in args.iter().enumerate() {
+                    closure_interpreter.environments.borrow_mut().define(
+                        params[i].lexeme.clone(),
+                        arg.clone(),
+                        Some(0),
+                    );
+                }
+                // Resolve the n-1 line in the body
+                #[allow(clippy::all)]
+                for i in 0..(body.len()) {
+                    closure_interpreter
+                        .interpret_body(body[i].as_ref())
+                        .map_err(|e| format!("inside {}: {}", name_clone, e))?;
+                    if closure_interpreter.returned() {
+                        break;
+                    }
+                }
+                match closure_interpreter.tail_call.take() {
+                    Some(next) => args = next,
+                    None => {
+                        let specials = closure_interpreter.specials.borrow();
+                        return Ok(specials
+                            .get("return")
+                            .cloned()
+                            .unwrap_or(LiteralValue::Nil));
+                    }
+                }
+            }
+        };
+        // The resolver made sure a pure function only depends on its arguments so
+        // its results can be cached, calls with instance arguments skip the cache
+        // since their fields can change between calls
+        #[allow(clippy::type_complexity)]
+        let mut fun: Rc<
+            dyn Fn(&Vec<LiteralValue>) -> Result<LiteralValue, Box<dyn Error>>,
+        > = Rc::from(func_impl);
+        if pure {
+            fun = memoized(fun);
+        }
+        // Create a Callable
+        LiteralValue::Callable {
+            id,
+            name: name.lexeme.clone(),
+            arity: AritySpec::exact(arity),
+            line: name.line_number,
+            fun,
+        }
+    }
+
+    // Evaluvate an Expression in the current Environment
+    fn evaluvate(&self, expr: &Expr) -> Result<LiteralValue, Box<dyn Error>> {
+        expr.evaluvate(self.environments.clone(), &self.locals, &self.options)
+    }
+
+    // Run a statement of a function body
+    // A return from a block expression in it is recorded like a return statement
+    pub fn interpret_body(&mut self, stmt: &Stmt) -> Result<(), Box<dyn Error>> {
+        match self.interpret(vec![stmt]) {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                // Any other error is passed on as it is
+                let Returned(value) = *e.downcast::<Returned>()?;
+                self.specials
+                    .borrow_mut()
+                    .insert("return".to_string(), value);
+                Ok(())
+            }
+        }
+    }
+
+    // Whether a return statement has run in this Interpreter
+    pub fn returned(&self) -> bool {
+        self.specials.borrow().contains_key("return")
+    }
+
+    // Record that the Variable or Assign with this id is distance scopes up
+    pub fn resolve(&mut self, id: usize, distance: usize) -> Result<(), Box<dyn Error>> {
+        self.locals.borrow_mut().insert(id, distance);
         Ok(())
     }
 
-    fn get_distance(&self, expr: &Expr) -> Option<usize> {
-        let addr = std::ptr::addr_of!(expr) as usize;
-        self.locals.borrow().get(&addr).copied()
+    // None for a global
+    pub fn get_distance(&self, id: usize) -> Option<usize> {
+        self.locals.borrow().get(&id).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::scanner::Scanner;
+
+    // Define the functions in source and call the one called name
+    fn call(source: &str, name: &str) -> LiteralValue {
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let stmts = Parser::new(tokens).parse().unwrap();
+        let mut interpreter = Interpreter::new();
+        interpreter.interpret(stmts.iter().collect()).unwrap();
+        let callable = interpreter.environments.borrow().get(name, None);
+        match callable {
+            Some(LiteralValue::Callable {
+                id: _,
+                name: _,
+                arity: _,
+                line: _,
+                fun,
+            }) => fun(&vec![]).unwrap(),
+            _ => panic!("{} is not a function", name),
+        }
+    }
+
+    fn run(interpreter: &mut Interpreter, source: &str) {
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let stmts = Parser::new(tokens).parse().unwrap();
+        interpreter.interpret(stmts.iter().collect()).unwrap();
+    }
+
+    #[test]
+    fn reset_between_runs() {
+        let mut interpreter = Interpreter::new();
+        interpreter.vm = true;
+        run(&mut interpreter, "var a = 1; func f() { return 1; }");
+        interpreter.reset();
+        run(&mut interpreter, "var b = 2;");
+
+        let env = interpreter.environments.borrow();
+        assert_eq!(env.get("a", None), None);
+        assert_eq!(env.get("f", None), None);
+        assert_eq!(env.get("b", None), Some(LiteralValue::Number(2.0)));
+        assert!(env.get("clock", None).is_some());
+        assert!(interpreter.specials.borrow().is_empty());
+        assert!(interpreter.vm);
+    }
+
+    // The expression of the single expression statement in source
+    fn expression(source: &str) -> Expr {
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        match Parser::new(tokens).parse().unwrap().remove(0) {
+            Stmt::Expression {
+                expression,
+                span: _,
+            } => expression,
+            _ => panic!("Expected an expression"),
+        }
+    }
+
+    #[test]
+    fn numeric_cond_falls_back_on_non_numbers() {
+        let mut interpreter = Interpreter::new();
+        run(&mut interpreter, "var i = 0; var n = 3;");
+        let cond = expression("i < n;");
+        let env = interpreter.environments.clone();
+
+        let fast = NumericCond::new(&cond, &env, &interpreter.locals).unwrap();
+        assert_eq!(fast.check(), Some(true));
+        run(&mut interpreter, "i = 3;");
+        assert_eq!(fast.check(), Some(false));
+        // Non numbers are left to evaluvate
+        run(&mut interpreter, "n = \"a\";");
+        assert_eq!(fast.check(), None);
+    }
+
+    // See the timings with `cargo test numeric_cond_benchmark -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn numeric_cond_benchmark() {
+        let mut interpreter = Interpreter::new();
+        run(&mut interpreter, "var i = 0; var n = 1000000;");
+        let cond = expression("i < n;");
+        let env = interpreter.environments.clone();
+        let locals = interpreter.locals.clone();
+        let options = interpreter.options.clone();
+
+        let start = std::time::Instant::now();
+        for _ in 0..1_000_000 {
+            assert_eq!(cond.evaluvate(env.clone(), &locals, &options).unwrap(), LiteralValue::Bool(true));
+        }
+        println!("1000000 evaluvated conditions took {:?}", start.elapsed());
+
+        let fast = NumericCond::new(&cond, &env, &locals).unwrap();
+        let start = std::time::Instant::now();
+        for _ in 0..1_000_000 {
+            assert_eq!(fast.check(), Some(true));
+        }
+        println!("1000000 fast conditions took {:?}", start.elapsed());
+    }
+
+    // Lenient nil belongs to one Interpreter and the functions it runs, others stay strict
+    #[test]
+    fn lenient_nil_is_per_interpreter() {
+        let mut lenient = Interpreter::new();
+        lenient.set_lenient_nil(true);
+        run(&mut lenient, "var a; func f() { return a + 1; } var b = f();");
+        assert_eq!(
+            lenient.environments.borrow().get("b", None),
+            Some(LiteralValue::Number(1.0))
+        );
+
+        let mut strict = Interpreter::new();
+        let tokens = Scanner::new("var a; var b = a + 1;").scan_tokens().unwrap();
+        let stmts = Parser::new(tokens).parse().unwrap();
+        assert!(strict.interpret(stmts.iter().collect()).is_err());
+    }
+
+    #[test]
+    fn native_hook_can_block_calls() {
+        let mut interpreter = Interpreter::new();
+        interpreter.set_native_hook(Some(Rc::new(|name, args| {
+            if name == "clock" {
+                return Err(format!("{} is not allowed", name).into());
+            }
+            assert_eq!(args.len(), 1);
+            Ok(())
+        })));
+        let source = "var c = char_code(\"a\"); clock();";
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let stmts = Parser::new(tokens).parse().unwrap();
+        let err = interpreter.interpret(stmts.iter().collect()).unwrap_err();
+        // Other Interpreters do not see the hook
+        run(&mut Interpreter::new(), "clock();");
+        interpreter.set_native_hook(None);
+
+        assert_eq!(err.to_string(), "clock is not allowed");
+        assert_eq!(
+            interpreter.environments.borrow().get("c", None),
+            Some(LiteralValue::Number(97.0))
+        );
+        run(&mut interpreter, "clock();");
+    }
+
+    #[test]
+    fn return_stops_the_function() {
+        let source = "func f() { if (true) { return 1; return 2; } return 3; }";
+        assert_eq!(call(source, "f"), LiteralValue::Number(1.0));
+
+        let source = "func f() { while (true) { { return 1; } return 2; } }";
+        assert_eq!(call(source, "f"), LiteralValue::Number(1.0));
     }
 }
@@ -2,11 +2,15 @@ mod environments;
 mod resolver;
 mod expr;
 mod interpreter;
+mod output;
 mod parser;
 mod scanner;
 mod stmt;
 mod tests;
-use interpreter::Interpreter;
+mod vm;
+use environments::Environment;
+use expr::LiteralValue;
+use interpreter::{Interpreter, Options};
 use parser::Parser;
 use resolver::Resolver;
 
@@ -19,80 +23,286 @@ use std::error::Error;
 use std::fs;
 use std::io;
 use std::io::Write;
+use std::path::PathBuf;
 use std::process::exit;
 
-fn run_string(contents: &str) -> Result<(),Box<dyn Error>> {
-    let interpreter = Rc::new(RefCell::new(Interpreter::new()));
-    run(interpreter.clone(), contents)
+fn run_string(interpreter: Rc<RefCell<Interpreter>>, contents: &str) -> Result<(), Box<dyn Error>> {
+    run(interpreter, contents)?;
+    Ok(())
 }
 
 // Run if file is given
-fn run_file(path: &str) -> Result<(), Box<dyn Error>> {
+fn run_file(interpreter: Rc<RefCell<Interpreter>>, path: &str) -> Result<(), Box<dyn Error>> {
     let contents = fs::read_to_string(path)?;
-    let interpreter = Rc::new(RefCell::new(Interpreter::new()));
-    run(interpreter.clone(), &contents)?;
+    run(interpreter, &contents)?;
     Ok(())
 }
 
 // Run for either promt or file
-fn run(interpreter: Rc<RefCell<Interpreter>>, contents: &str) -> Result<(), Box<dyn Error>> {
+// Returns the value of the last statement if it was an expression
+fn run(
+    interpreter: Rc<RefCell<Interpreter>>,
+    contents: &str,
+) -> Result<Option<LiteralValue>, Box<dyn Error>> {
     let mut scanner = Scanner::new(contents);
     let tokens = scanner.scan_tokens()?;
 
     let mut parser = Parser::new(tokens);
+    if interpreter.borrow().print_function {
+        parser = parser.with_print_function();
+    }
 
     let stmts = parser.parse()?;
     let mut resolver = Resolver::new(interpreter.clone());
     resolver.resolve_many(&stmts.iter().collect())?;
-    interpreter.borrow_mut().interpret(stmts.iter().collect())?;
+    let value = interpreter.borrow_mut().interpret(stmts.iter().collect());
+    // Printed output is buffered, show it before any error or the next prompt
+    output::flush()?;
+
+    value
+}
+
+// Check if file is given with --check
+fn check_file(
+    interpreter: Rc<RefCell<Interpreter>>,
+    path: &str,
+    max_line_length: Option<usize>,
+) -> Result<(), Box<dyn Error>> {
+    let contents = fs::read_to_string(path)?;
+    check(interpreter, &contents, max_line_length)
+}
 
+// Scan, parse and resolve without running anything and print the warnings
+// Warnings carry a span which is turned into a line number here as only we have the source
+// With --max-line-length=<n> long lines are warned about too
+fn check(
+    interpreter: Rc<RefCell<Interpreter>>,
+    contents: &str,
+    max_line_length: Option<usize>,
+) -> Result<(), Box<dyn Error>> {
+    let mut scanner = Scanner::new(contents);
+    if let Some(max) = max_line_length {
+        scanner = scanner.with_max_line_length(max);
+    }
+    let tokens = scanner.scan_tokens()?;
+
+    let mut parser = Parser::new(tokens);
+    if interpreter.borrow().print_function {
+        parser = parser.with_print_function();
+    }
+
+    let stmts = parser.parse()?;
+    let mut resolver = Resolver::new(interpreter);
+    resolver.resolve_many(&stmts.iter().collect())?;
+    let mut warnings = scanner.warnings;
+    warnings.extend(resolver.warnings);
+    warnings.sort_by_key(|(span, _)| *span);
+    for ((start, _), msg) in warnings {
+        let line = contents[..start].matches('\n').count() + 1;
+        println!("Warning: Line {}: {}", line, msg);
+    }
     Ok(())
 }
 
+// Read lines till we have a complete statement
+// Stops early on EOF, an empty line, 'exit' or a '.' meta-command
+fn read_statement(prompt: &str) -> Result<String, Box<dyn Error>> {
+    let mut buffer = String::new();
+    while !(buffer.trim().ends_with(";") || buffer.trim().ends_with("}")) {
+        output::flush()?;
+        print!("{}", prompt);
+        io::stdout().flush().unwrap();
+        let stdin = io::stdin();
+        stdin.read_line(&mut buffer)?;
+        let line = buffer.trim();
+        if line == "exit" || line.is_empty() || line.starts_with('.') {
+            break;
+        }
+    }
+    Ok(buffer)
+}
+
+// Where the REPL history is kept unless --repl-history=<path> is given
+fn default_history_path() -> Option<PathBuf> {
+    env::var("HOME")
+        .ok()
+        .map(|home| PathBuf::from(home).join(".tox_history"))
+}
+
+// Prefix of echoed results in the REPL unless TOX_ECHO or '.echo' changes it
+const DEFAULT_ECHO: &str = "=> ";
+
 // Run if no file is given
-fn run_prompt() -> Result<(), Box<dyn Error>> {
-    let interpreter = Rc::new(RefCell::new(Interpreter::new()));
+// The prompt and the prefix of echoed results come from TOX_PROMPT and TOX_ECHO and can be
+// changed with the '.prompt' and '.echo' commands
+// '.echo off' or TOX_ECHO=off stops showing results, '.echo on' shows them with the default prefix
+// Every submitted statement is appended to the history file, '.history' lists them
+// '.clear' forgets every variable and function defined so far
+// '.inspect <code>' runs code and shows its value together with the type
+fn run_prompt(
+    interpreter: Rc<RefCell<Interpreter>>,
+    history_path: Option<PathBuf>,
+) -> Result<(), Box<dyn Error>> {
+    let mut prompt = env::var("TOX_PROMPT").unwrap_or("> ".to_string());
+    // None while results are not shown
+    let mut echo = match env::var("TOX_ECHO") {
+        Ok(echo) if echo == "off" => None,
+        Ok(echo) => Some(echo),
+        Err(_) => Some(DEFAULT_ECHO.to_string()),
+    };
+    let mut history: Vec<String> = match &history_path {
+        Some(path) => fs::read_to_string(path)
+            .map(|contents| contents.lines().map(|line| line.to_string()).collect())
+            .unwrap_or_default(),
+        None => vec![],
+    };
     loop {
-        let mut buffer = String::new();
-        while !(buffer.trim().ends_with(";") || buffer.trim().ends_with("}")) {
-            print!("> ");
-            io::stdout().flush().unwrap();
-            let stdin = io::stdin();
-            stdin.read_line(&mut buffer)?;
-            if buffer.trim() == "exit" || buffer.trim() == "" {
-                exit(0);
+        let buffer = read_statement(&prompt)?;
+        if buffer.trim() == "exit" || buffer.trim() == "" {
+            exit(0);
+        }
+        // Keep trailing spaces of the settings, only drop the newline
+        let line = buffer.trim_start().trim_end_matches(['\n', '\r']);
+        if let Some(new_prompt) = line.strip_prefix(".prompt ") {
+            prompt = new_prompt.to_string();
+            continue;
+        }
+        if let Some(new_echo) = line.strip_prefix(".echo ") {
+            echo = match new_echo.trim() {
+                "off" => None,
+                "on" => Some(DEFAULT_ECHO.to_string()),
+                _ => Some(new_echo.to_string()),
+            };
+            continue;
+        }
+        if line == ".clear" {
+            interpreter.borrow_mut().reset();
+            continue;
+        }
+        if let Some(code) = line.strip_prefix(".inspect ") {
+            match run(interpreter.clone(), code) {
+                Ok(Some(value)) => println!("{}", value.inspect()),
+                Ok(None) => println!("Nothing to inspect, expected an expression"),
+                Err(e) => println!("{}", e),
             }
+            println!();
+            continue;
+        }
+        if line == ".history" {
+            for (i, entry) in history.iter().enumerate() {
+                println!("{}: {}", i + 1, entry);
+            }
+            continue;
+        }
+        let entry = buffer.trim().to_string();
+        if let Some(path) = &history_path {
+            let mut file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+            writeln!(file, "{}", entry)?;
         }
+        history.extend(entry.lines().map(|line| line.to_string()));
         match run(interpreter.clone(), &buffer) {
-            Ok(_) => (),
+            Ok(Some(value)) => {
+                if let Some(echo) = &echo {
+                    println!("{}{}", echo, value.display_string());
+                }
+            }
+            Ok(None) => (),
             Err(e) => println!("{}", e),
         }
         println!();
     }
 }
 
+// Nested prompt started by breakpoint()
+// Runs in the Environment breakpoint() was called from so variables can be read and assigned,
+// execution resumes on '.continue'
+fn run_breakpoint(env: Rc<RefCell<Environment>>, options: &Options) -> Result<(), Box<dyn Error>> {
+    let interpreter = Rc::new(RefCell::new(Interpreter::new()));
+    interpreter.borrow_mut().environments = env;
+    interpreter.borrow_mut().options = options.clone();
+    loop {
+        let buffer = read_statement("(breakpoint) > ")?;
+        match buffer.trim() {
+            ".continue" | "" => return Ok(()),
+            line if line.starts_with('.') => println!("Unknown command {}", line),
+            _ => {
+                if let Err(e) = run(interpreter.clone(), &buffer) {
+                    println!("{}", e);
+                }
+            }
+        }
+    }
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
 
-    if args.len() == 1 {
-        if let Err(e) = run_prompt() {
+    // Flags can be given anywhere and are removed before matching the positional args
+    let (flags, args): (Vec<String>, Vec<String>) =
+        args.into_iter().partition(|arg| arg.starts_with("--"));
+    let trace = flags.iter().any(|flag| flag == "--trace");
+    let use_vm = flags.iter().any(|flag| flag == "--vm");
+    let lenient_nil = flags.iter().any(|flag| flag == "--lenient-nil");
+    let print_function = flags.iter().any(|flag| flag == "--print-function");
+    let check_only = flags.iter().any(|flag| flag == "--check");
+    let max_line_length = match flags
+        .iter()
+        .find_map(|flag| flag.strip_prefix("--max-line-length="))
+        .map(|n| n.parse::<usize>())
+    {
+        Some(Ok(n)) => Some(n),
+        Some(Err(_)) => {
+            println!("Error: --max-line-length expects a number");
+            exit(64);
+        }
+        None => None,
+    };
+    let history_path = flags
+        .iter()
+        .find_map(|flag| flag.strip_prefix("--repl-history="))
+        .map(PathBuf::from)
+        .or_else(default_history_path);
+
+    let interpreter = Rc::new(RefCell::new(Interpreter::new()));
+    interpreter.borrow_mut().trace = trace;
+    interpreter.borrow_mut().vm = use_vm;
+    interpreter.borrow_mut().set_lenient_nil(lenient_nil);
+    interpreter.borrow_mut().print_function = print_function;
+    interpreter
+        .borrow_mut()
+        .set_breakpoint_hook(Some(Rc::new(run_breakpoint)));
+
+    if check_only && args.len() == 2 {
+        if let Err(e) = check_file(interpreter, &args[1], max_line_length) {
+            println!("Error: {}", e);
+            exit(1);
+        }
+    } else if check_only && args.len() == 3 && args[1] == "e" {
+        if let Err(e) = check(interpreter, &args[2], max_line_length) {
+            println!("Error: {}", e);
+            exit(1);
+        }
+    } else if args.len() == 1 {
+        if let Err(e) = run_prompt(interpreter, history_path) {
             println!("Error: {}", e);
             exit(1);
         }
     } else if args.len() == 2 {
-        if let Err(e) = run_file(&args[1]) {
+        if let Err(e) = run_file(interpreter, &args[1]) {
             println!("Error: {}", e);
             exit(1);
         }
     } else if args.len() == 3 && args[1] == "e" {
-        if let Err(e) = run_string(&args[2]){
+        if let Err(e) = run_string(interpreter, &args[2]) {
             println!("Error: {}", e);
             exit(1);
         };
     } else {
-        println!("Usage: script");
+        println!("Usage: script [--trace] [--vm] [--lenient-nil] [--print-function] [--repl-history=<path>]");
+        println!("\tOR");
+        println!("Usage: script [--trace] [--vm] [--lenient-nil] [--print-function] [file path]");
         println!("\tOR");
-        println!("Usage: script [file path]");
+        println!("Usage: script --check [--max-line-length=<n>] [file path]");
         exit(64);
     }
 }
@@ -0,0 +1,27 @@
+use std::cell::RefCell;
+use std::io::{self, BufWriter, Stdout, Write};
+
+// Everything a program prints goes through this buffer instead of a syscall per line
+// It has to be flushed before anything else is shown or read, see flush
+thread_local! {
+    static OUT: RefCell<BufWriter<Stdout>> = RefCell::new(BufWriter::new(io::stdout()));
+}
+
+// Buffer s as program output
+pub fn write(s: &str) -> io::Result<()> {
+    OUT.with(|out| out.borrow_mut().write_all(s.as_bytes()))
+}
+
+// Buffer s and a newline as program output
+pub fn write_line(s: &str) -> io::Result<()> {
+    OUT.with(|out| {
+        let mut out = out.borrow_mut();
+        out.write_all(s.as_bytes())?;
+        out.write_all(b"\n")
+    })
+}
+
+// Write out everything buffered so far
+pub fn flush() -> io::Result<()> {
+    OUT.with(|out| out.borrow_mut().flush())
+}
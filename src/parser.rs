@@ -5,19 +5,67 @@ use crate::stmt::Stmt;
 use crate::TokenType;
 use std::error::Error;
 
+// Default limit on how deep expressions and blocks can be nested
+const MAX_NESTING: usize = 64;
+
+// Names a variable can be annotated with, the same as LiteralValue::to_type gives
+const TYPE_NAMES: [&str; 7] = [
+    "Number", "String", "Boolean", "Nil", "Callable", "Class", "Instance",
+];
+
 pub struct Parser {
     tokens: Vec<Token>,
     current: usize,
+    // How many blocks deep we are, used to recover from errors inside a block
+    depth: usize,
+    // How deep the recursive descent currently is and how deep it may go before erroring instead
+    // of overflowing the stack
+    nesting: usize,
+    max_nesting: usize,
+    // Parse 'print' as a call to the print native instead of a statement
+    print_function: bool,
+}
+
+// Span covering just the given token
+fn token_span(token: &Token) -> Span {
+    (token.offset, token.offset + token.length)
 }
 
 #[derive(Debug)]
 enum FunctionKind {
     Function,
+    Method,
 }
 
 impl Parser {
     pub fn new(tokens: Vec<Token>) -> Self {
-        Self { tokens, current: 0 }
+        Self {
+            tokens,
+            current: 0,
+            depth: 0,
+            nesting: 0,
+            max_nesting: MAX_NESTING,
+            print_function: false,
+        }
+    }
+
+    // Change how deep expressions and blocks can be nested
+    #[allow(dead_code)]
+    pub fn with_max_nesting(mut self, max_nesting: usize) -> Self {
+        self.max_nesting = max_nesting;
+        self
+    }
+
+    // Treat 'print' as the print native, both 'print(x);' and 'print x;' become calls
+    // The keyword becomes a plain name so 'var p = print;' works as well
+    pub fn with_print_function(mut self) -> Self {
+        self.print_function = true;
+        for token in self.tokens.iter_mut() {
+            if token.token_type == TokenType::Print {
+                token.token_type = TokenType::Identifier;
+            }
+        }
+        self
     }
 
     // The Main parse function that is called from outside
@@ -35,6 +83,7 @@ impl Parser {
                 Ok(s) => stmts.push(s),
                 Err(e) => {
                     errors.push(e);
+                    self.nesting = 0;
                     // If we get a error we need to move the pointer forward to where we can
                     // continue parsing
                     self.synchronize();
@@ -58,15 +107,56 @@ impl Parser {
     fn declaration(&mut self) -> Result<Stmt, Box<dyn Error>> {
         if self.match_token(TokenType::Var) {
             self.var_declaration()
+        } else if self.match_token(TokenType::Class) {
+            self.class_declaration()
+        } else if self.match_token(Lazy) {
+            self.lazy_declaration()
         } else if self.match_token(Func) {
             self.function(FunctionKind::Function)
+        } else if self.match_token(At) {
+            self.attributed_function()
         } else {
             self.statement()
         }
     }
 
+    // Class declaration, the body is a list of methods without the 'func' keyword and fields
+    // with a default, 'name = value;'
+    fn class_declaration(&mut self) -> Result<Stmt, Box<dyn Error>> {
+        let start = self.previous().offset;
+        let name = self.consume(TokenType::Identifier, "Expected class name")?;
+        self.consume(TokenType::LeftBrace, "Expected '{' before class body")?;
+
+        let mut fields = vec![];
+        let mut methods = vec![];
+        while !self.check(RightBrace) && !self.is_at_end() {
+            if self.check(Identifier) && self.tokens[self.current + 1].token_type == Equal {
+                let field = self.advance().clone();
+                self.advance();
+                let value = self.expression()?;
+                self.consume(TokenType::Semicolon, "Expected ';' after field default")?;
+                fields.push((field, value));
+            } else {
+                methods.push(self.function(FunctionKind::Method)?);
+            }
+        }
+        self.consume(TokenType::RightBrace, "Expected '}' after class body")?;
+
+        Ok(Stmt::Class {
+            name,
+            fields,
+            methods,
+            span: self.span_from(start),
+        })
+    }
+
     // Function declaration
     fn function(&mut self, kind: FunctionKind) -> Result<Stmt, Box<dyn Error>> {
+        // Methods have no keyword in front of their name
+        let start = match kind {
+            FunctionKind::Function => self.previous().offset,
+            FunctionKind::Method => self.peek().offset,
+        };
         // Get the function name
         let token = self.consume(
             TokenType::Identifier,
@@ -108,7 +198,7 @@ impl Parser {
         // The body of the function which is basically a block
         // Will return a array of statements
         let body = match self.block()? {
-            Stmt::Block { stmts } => stmts,
+            Stmt::Block { stmts, span: _ } => stmts,
             _ => panic!("Block statement parsed something that was not a block"),
         };
 
@@ -117,22 +207,73 @@ impl Parser {
             name: token,
             params,
             body,
+            pure: false,
+            span: self.span_from(start),
         })
     }
 
+    // A function declaration with an '@attribute' in front, the only attribute is @pure
+    fn attributed_function(&mut self) -> Result<Stmt, Box<dyn Error>> {
+        let start = self.previous().offset;
+        let attribute = self.consume(TokenType::Identifier, "Expected attribute name after '@'")?;
+        if attribute.lexeme != "pure" {
+            return Err(format!(
+                "Line {}: Unknown attribute '@{}'",
+                attribute.line_number, attribute.lexeme
+            )
+            .into());
+        }
+        self.consume(TokenType::Func, "Expected 'func' after '@pure'")?;
+        match self.function(FunctionKind::Function)? {
+            Stmt::Function {
+                name,
+                params,
+                body,
+                pure: _,
+                span,
+            } => Ok(Stmt::Function {
+                name,
+                params,
+                body,
+                pure: true,
+                span: (start, span.1),
+            }),
+            _ => panic!("Function declaration parsed something that was not a function"),
+        }
+    }
+
     // Encountered the 'var' keyword
     fn var_declaration(&mut self) -> Result<Stmt, Box<dyn Error>> {
+        let start = self.previous().offset;
         // Get the variable name
         let token = self.consume(TokenType::Identifier, "Expect variable name.")?;
 
+        // var a: Number = 1; -> annotated with a type
+        let type_name = if self.match_token(Colon) {
+            let type_name = self.consume(TokenType::Identifier, "Expected type name after ':'")?;
+            if !TYPE_NAMES.contains(&type_name.lexeme.as_str()) {
+                return Err(format!(
+                    "Line {}: Unknown type '{}'",
+                    type_name.line_number, type_name.lexeme
+                )
+                .into());
+            }
+            Some(type_name)
+        } else {
+            None
+        };
+
         // Check if the variable is initialized
         // var a; -> declaration
         // var a=1; -> initialized
         let initializer = if self.match_token(Equal) {
             self.expression()?
         } else {
+            // Nothing was written so the nil is an empty span right after the name
+            let end = token.offset + token.length;
             Expr::Literal {
                 literal: LiteralValue::Nil,
+                span: (end, end),
             }
         };
 
@@ -144,15 +285,44 @@ impl Parser {
         Ok(Stmt::Var {
             name: token,
             initializer,
+            type_name,
+            lazy: false,
+            span: self.span_from(start),
         })
     }
 
+    // 'lazy var', a normal var declaration with the flag set
+    fn lazy_declaration(&mut self) -> Result<Stmt, Box<dyn Error>> {
+        let start = self.previous().offset;
+        self.consume(TokenType::Var, "Expected 'var' after 'lazy'")?;
+        match self.var_declaration()? {
+            Stmt::Var {
+                name,
+                initializer,
+                type_name,
+                lazy: _,
+                span,
+            } => Ok(Stmt::Var {
+                name,
+                initializer,
+                type_name,
+                lazy: true,
+                span: (start, span.1),
+            }),
+            _ => panic!("Var declaration parsed something that was not a var"),
+        }
+    }
+
     // Here we get the statements that have a lower presedence than in the declaration
     fn statement(&mut self) -> Result<Stmt, Box<dyn Error>> {
-        if self.match_token(TokenType::Print) {
+        if self.print_function && self.peek().lexeme == "print" {
+            self.print_call()
+        } else if self.match_token(TokenType::Print) {
             self.print_expression()
         } else if self.match_token(TokenType::LeftBrace) {
             self.block()
+        } else if self.match_token(TokenType::Scope) {
+            self.scope_block()
         } else if self.match_token(TokenType::If) {
             self.if_statement()
         } else if self.match_token(TokenType::While) {
@@ -168,6 +338,7 @@ impl Parser {
 
     fn return_statement(&mut self) -> Result<Stmt, Box<dyn Error>> {
         let keyword = self.previous().clone();
+        let start = keyword.offset;
         let value = if !self.check(Semicolon) {
             Some(self.expression()?)
         } else {
@@ -175,11 +346,18 @@ impl Parser {
         };
         self.consume(TokenType::Semicolon, "Expected ';' after return value")?;
 
-        Ok(Stmt::Return { keyword, value })
+        Ok(Stmt::Return {
+            keyword,
+            value,
+            span: self.span_from(start),
+        })
     }
 
     // For loop is syntactic sugar and uses while loop under the hood
+    // Every node made up for the desugaring gets the span of the whole for statement
     fn for_statement(&mut self) -> Result<Stmt, Box<dyn Error>> {
+        let start = self.previous().offset;
+        self.enter()?;
         self.consume(TokenType::LeftParen, "Expect '(' after 'for'.")?;
         // Check if a variable is initialized, assigned a new val or is not given at all
         let initializer = if self.match_token(Semicolon) {
@@ -210,21 +388,28 @@ impl Parser {
         // The body of a for loop is basically a block
         // We append the increment to the end of said block
         let body = if let Some(expr) = increment {
+            let body = self.statement()?;
+            let span = self.span_from(start);
             let stmts = vec![
-                Box::from(self.statement()?),
-                Box::from(Stmt::Expression { expression: expr }),
+                Box::from(body),
+                Box::from(Stmt::Expression {
+                    span: expr.span(),
+                    expression: expr,
+                }),
             ];
-            Stmt::Block { stmts }
+            Stmt::Block { stmts, span }
         } else {
             self.statement()?
         };
+        let span = self.span_from(start);
 
         // If there is no condition we set it to True
         let cond = if let Some(s) = cond {
             s
         } else {
             Expr::Literal {
-                literal: LiteralValue::True,
+                literal: LiteralValue::Bool(true),
+                span,
             }
         };
 
@@ -232,6 +417,8 @@ impl Parser {
         let mut body_while = Stmt::WhileLoop {
             cond,
             body: Box::from(body),
+            else_branch: None,
+            span,
         };
 
         // If we have a increment we nest the while loop in another block and initalize the
@@ -239,25 +426,46 @@ impl Parser {
         if let Some(expr) = initializer {
             body_while = Stmt::Block {
                 stmts: vec![Box::from(expr), Box::from(body_while)],
+                span,
             };
         }
+        self.nesting -= 1;
 
         Ok(body_while)
     }
 
     // While loop is basically a reoccouring block statement
+    // An optional else branch runs only if the body never executed
     fn while_statement(&mut self) -> Result<Stmt, Box<dyn Error>> {
+        let start = self.previous().offset;
+        self.enter()?;
         self.consume(TokenType::LeftParen, "Expect '(' after 'while'.")?;
         let cond = self.expression()?;
         self.consume(TokenType::RightParen, "Expect ')' after condition.")?;
         // Should return a Block Statement
         let body = Box::from(self.statement()?);
+        // Only a block body takes an else, otherwise `if (a) while (b) s; else t;` would give the
+        // else to the while instead of the if
+        let is_block = matches!(body.as_ref(), Stmt::Block { .. });
+        let else_branch = if is_block && self.match_token(Else) {
+            Some(Box::from(self.statement()?))
+        } else {
+            None
+        };
+        self.nesting -= 1;
 
-        Ok(Stmt::WhileLoop { cond, body })
+        Ok(Stmt::WhileLoop {
+            cond,
+            body,
+            else_branch,
+            span: self.span_from(start),
+        })
     }
 
     // Get the condition/predicate and then_branch and else_branch if it exists
     fn if_statement(&mut self) -> Result<Stmt, Box<dyn Error>> {
+        let start = self.previous().offset;
+        self.enter()?;
         self.consume(TokenType::LeftParen, "Expected '(' after 'if'")?;
         let predicate = self.expression()?;
         self.consume(TokenType::RightParen, "Expected ')' after if-predicate")?;
@@ -268,17 +476,27 @@ impl Parser {
         } else {
             None
         };
+        self.nesting -= 1;
 
         Ok(Stmt::IfElse {
             predicate,
             then_branch,
             else_branch,
+            span: self.span_from(start),
         })
     }
 
     // Creates a array of statements till we reach a '}'
     fn block(&mut self) -> Result<Stmt, Box<dyn Error>> {
+        let start = self.previous().offset;
+        self.block_from(start)
+    }
+
+    // The rest of a block after its '{', the span starts at start
+    fn block_from(&mut self, start: usize) -> Result<Stmt, Box<dyn Error>> {
         let mut stmts = vec![];
+        self.enter()?;
+        self.depth += 1;
 
         while !self.check(RightBrace) && !self.is_at_end() {
             let stmt = self.declaration()?;
@@ -286,43 +504,109 @@ impl Parser {
         }
 
         self.consume(TokenType::RightBrace, "Expect '}' after block.")?;
+        self.depth -= 1;
+        self.nesting -= 1;
+
+        Ok(Stmt::Block {
+            stmts,
+            span: self.span_from(start),
+        })
+    }
 
-        Ok(Stmt::Block { stmts })
+    // 'scope { ... }' is the same as a bare block, the keyword only makes it stand out
+    fn scope_block(&mut self) -> Result<Stmt, Box<dyn Error>> {
+        let start = self.previous().offset;
+        self.consume(TokenType::LeftBrace, "Expect '{' after 'scope'.")?;
+        self.block_from(start)
     }
 
     // Printing branch
     fn print_expression(&mut self) -> Result<Stmt, Box<dyn Error>> {
+        let start = self.previous().offset;
         let val = self.expression()?;
-        self.consume(TokenType::Semicolon, "Expected ';' after value")?;
-        Ok(Stmt::Print { expression: val })
+        self.end_statement("Expected ';' after value")?;
+        Ok(Stmt::Print {
+            expression: val,
+            span: self.span_from(start),
+        })
+    }
+
+    // 'print' read as a name, with parens it is parsed like any other call so 'print(a) + 1;'
+    // still works, without them the rest of the statement is the only argument
+    fn print_call(&mut self) -> Result<Stmt, Box<dyn Error>> {
+        let keyword = self.advance().clone();
+        if self.check(LeftParen) {
+            self.current -= 1;
+            return self.expression_statement();
+        }
+        let callee = Expr::Variable {
+            id: next_expr_id(),
+            span: token_span(&keyword),
+            name: keyword.clone(),
+        };
+        let arg = self.expression()?;
+        self.end_statement("Expected ';' after value")?;
+        let expression = Expr::Call {
+            span: (keyword.offset, arg.span().1),
+            callee: Box::from(callee),
+            paren: keyword,
+            args: vec![arg],
+        };
+        Ok(Stmt::Expression {
+            span: self.span_from(expression.span().0),
+            expression,
+        })
     }
 
     // Normal expression
     fn expression_statement(&mut self) -> Result<Stmt, Box<dyn Error>> {
         let expr = self.expression()?;
-        self.consume(TokenType::Semicolon, "Expected ';' after expression")?;
-        Ok(Stmt::Expression { expression: expr })
+        self.end_statement("Expected ';' after expression")?;
+        Ok(Stmt::Expression {
+            span: self.span_from(expr.span().0),
+            expression: expr,
+        })
     }
 
     fn expression(&mut self) -> Result<Expr, Box<dyn Error>> {
-        self.assignment()
+        self.enter()?;
+        let expr = self.assignment()?;
+        self.nesting -= 1;
+        Ok(expr)
     }
 
     // Assigning values to variables
     fn assignment(&mut self) -> Result<Expr, Box<dyn Error>> {
-        let lhs_expr = self.or()?;
+        let lhs_expr = self.ternary()?;
 
         // Is the variable initialized
         if self.match_token(Equal) {
             let _eq = self.previous();
             // Get the RHS
+            self.enter()?;
             let rhs_expr = self.assignment()?;
+            self.nesting -= 1;
+            let span = (lhs_expr.span().0, rhs_expr.span().1);
             match lhs_expr {
                 // Create the Expression
-                Expr::Variable { name } => {
+                Expr::Variable { id: _, name, span: _ } => {
                     return Ok(Expr::Assign {
+                        id: next_expr_id(),
                         name,
                         value: Box::from(rhs_expr),
+                        span,
+                    });
+                }
+                Expr::Get {
+                    object,
+                    name,
+                    span: _,
+                } => {
+                    return Ok(Expr::Set {
+                        object,
+                        name,
+                        value: Box::from(rhs_expr),
+                        span,
                     });
                 }
                 _ => {
@@ -333,48 +617,75 @@ impl Parser {
         Ok(lhs_expr)
     }
 
+    // Conditional 'cond ? a : b', the else branch is parsed here again so
+    // 'a ? b : c ? d : e' groups as 'a ? b : (c ? d : e)'
+    fn ternary(&mut self) -> Result<Expr, Box<dyn Error>> {
+        let cond = self.or()?;
+
+        if self.match_token(Question) {
+            let then_branch = self.expression()?;
+            self.consume(TokenType::Colon, "Expected ':' after then branch of '?'")?;
+            self.enter()?;
+            let else_branch = self.ternary()?;
+            self.nesting -= 1;
+            return Ok(Expr::Ternary {
+                span: (cond.span().0, else_branch.span().1),
+                cond: Box::from(cond),
+                then_branch: Box::from(then_branch),
+                else_branch: Box::from(else_branch),
+            });
+        }
+        Ok(cond)
+    }
+
     // OR logical operator
     fn or(&mut self) -> Result<Expr, Box<dyn Error>> {
-        let lhs_expr = self.and()?;
+        let mut lhs_expr = self.and()?;
 
-        if self.match_token(Or) {
+        while self.match_token(Or) {
             let op = self.previous().clone();
             let rhs_expr = self.and()?;
-            return Ok(Expr::Logical {
+            let span = (lhs_expr.span().0, rhs_expr.span().1);
+            lhs_expr = Expr::Logical {
                 left: Box::from(lhs_expr),
                 operator: op,
                 right: Box::from(rhs_expr),
-            });
+                span,
+            };
         }
         Ok(lhs_expr)
     }
 
     // AND logical operator
     fn and(&mut self) -> Result<Expr, Box<dyn Error>> {
-        let lhs_expr = self.equality()?;
+        let mut lhs_expr = self.equality()?;
 
-        if self.match_token(And) {
+        while self.match_token(And) {
             let op = self.previous().clone();
             let rhs_expr = self.equality()?;
-            return Ok(Expr::Logical {
+            let span = (lhs_expr.span().0, rhs_expr.span().1);
+            lhs_expr = Expr::Logical {
                 left: Box::from(lhs_expr),
                 operator: op,
                 right: Box::from(rhs_expr),
-            });
+                span,
+            };
         }
         Ok(lhs_expr)
     }
 
-    // Creates Expression for == or !=
+    // Creates Expression for ==, != or ~=
     fn equality(&mut self) -> Result<Expr, Box<dyn Error>> {
         let mut lhs_expr = self.comparision()?;
-        while self.match_tokens(vec![BangEqual, EqualEqual]) {
+        while self.match_tokens(vec![BangEqual, EqualEqual, TildeEqual]) {
             let op = self.previous().clone();
             let rhs_expr = self.comparision()?;
+            let span = (lhs_expr.span().0, rhs_expr.span().1);
             lhs_expr = Expr::Binary {
                 left: Box::from(lhs_expr),
                 operator: op,
                 right: Box::from(rhs_expr),
+                span,
             };
         }
         Ok(lhs_expr)
@@ -387,10 +698,12 @@ impl Parser {
         while self.match_tokens(vec![Greater, GreaterEqual, LessEqual, Less]) {
             let op = self.previous().clone();
             let rhs_expr = self.term()?;
+            let span = (lhs_expr.span().0, rhs_expr.span().1);
             lhs_expr = Expr::Binary {
                 left: Box::from(lhs_expr),
                 operator: op,
                 right: Box::from(rhs_expr),
+                span,
             }
         }
 
@@ -404,27 +717,31 @@ impl Parser {
         while self.match_tokens(vec![Minus, Plus]) {
             let op = self.previous().clone();
             let rhs_expr = self.factor()?;
+            let span = (lhs_expr.span().0, rhs_expr.span().1);
             lhs_expr = Expr::Binary {
                 left: Box::from(lhs_expr),
                 operator: op,
                 right: Box::from(rhs_expr),
+                span,
             }
         }
 
         Ok(lhs_expr)
     }
 
-    // Resolves binay operators such as / or *
+    // Resolves binay operators such as /, * or %
     fn factor(&mut self) -> Result<Expr, Box<dyn Error>> {
         let mut lhs_expr = self.unary()?;
 
-        while self.match_tokens(vec![Slash, Star]) {
+        while self.match_tokens(vec![Slash, Star, Percent]) {
             let op = self.previous().clone();
             let rhs_expr = self.unary()?;
+            let span = (lhs_expr.span().0, rhs_expr.span().1);
             lhs_expr = Expr::Binary {
                 left: Box::from(lhs_expr),
                 operator: op,
                 right: Box::from(rhs_expr),
+                span,
             }
         }
 
@@ -435,21 +752,61 @@ impl Parser {
     fn unary(&mut self) -> Result<Expr, Box<dyn Error>> {
         if self.match_tokens(vec![Minus, Bang]) {
             let op = self.previous().clone();
+            self.enter()?;
             let rhs_expr = self.unary()?;
+            self.nesting -= 1;
+            let span = (op.offset, rhs_expr.span().1);
             return Ok(Expr::Unary {
                 operator: op,
                 right: Box::from(rhs_expr),
+                span,
+            });
+        }
+        self.exponent()
+    }
+
+    // Power operator '**', binds tighter than unary so -2 ** 2 is -(2 ** 2)
+    // The right side is parsed with unary which comes back here, making it right associative
+    fn exponent(&mut self) -> Result<Expr, Box<dyn Error>> {
+        let lhs_expr = self.call()?;
+
+        if self.match_token(StarStar) {
+            let op = self.previous().clone();
+            self.enter()?;
+            let rhs_expr = self.unary()?;
+            self.nesting -= 1;
+            let span = (lhs_expr.span().0, rhs_expr.span().1);
+            return Ok(Expr::Binary {
+                left: Box::from(lhs_expr),
+                operator: op,
+                right: Box::from(rhs_expr),
+                span,
             });
         }
-        self.call()
+        Ok(lhs_expr)
     }
 
-    // Function call
+    // Function call, property access, or a postfix '!' which can never start an expression so
+    // it is not a not
     fn call(&mut self) -> Result<Expr, Box<dyn Error>> {
         let mut expr = self.primary()?;
         loop {
             if self.match_token(LeftParen) {
                 expr = self.finish_call(expr)?;
+            } else if self.match_token(Dot) {
+                let name = self.consume(TokenType::Identifier, "Expected property name after '.'")?;
+                expr = Expr::Get {
+                    span: (expr.span().0, name.offset + name.length),
+                    object: Box::from(expr),
+                    name,
+                };
+            } else if self.match_token(Bang) {
+                let operator = self.previous().clone();
+                expr = Expr::Postfix {
+                    span: (expr.span().0, operator.offset + operator.length),
+                    operator,
+                    operand: Box::from(expr),
+                };
             } else {
                 break;
             }
@@ -482,6 +839,7 @@ impl Parser {
         let paren = self.consume(TokenType::RightParen, "Expexted ')' after arguments")?;
         // Create a Call Expression
         Ok(Expr::Call {
+            span: (callee.span().0, paren.offset + paren.length),
             callee: Box::from(callee),
             paren,
             args,
@@ -495,22 +853,35 @@ impl Parser {
         let result;
         match token.token_type {
             LeftParen => {
+                let start = token.offset;
                 self.advance();
                 let expr = self.expression()?;
                 self.consume(TokenType::RightParen, "Expected ')'")?;
                 result = Expr::Grouping {
                     expression: Box::from(expr),
+                    span: self.span_from(start),
                 };
             }
             Number | String_ | True | False | Nil => {
                 result = Expr::Literal {
                     literal: LiteralValue::from_token(token),
+                    span: token_span(token),
                 };
                 self.advance();
             }
             Identifier => {
                 result = Expr::Variable {
+                    id: next_expr_id(),
                     name: token.clone(),
+                    span: token_span(token),
+                };
+                self.advance();
+            }
+            This => {
+                result = Expr::This {
+                    id: next_expr_id(),
+                    keyword: token.clone(),
+                    span: token_span(token),
                 };
                 self.advance();
             }
@@ -518,6 +889,10 @@ impl Parser {
                 self.advance();
                 result = self.function_expression()?;
             }
+            LeftBrace => {
+                self.advance();
+                result = self.block_expression()?;
+            }
             _ => {
                 return Err(format!("{:?} is not a primary", self.peek()).into());
             }
@@ -525,7 +900,51 @@ impl Parser {
         Ok(result)
     }
 
+    // A block in expression position, the value is the expression before the '}' that has no ';'
+    // If every statement ends with a ';' the value is nil
+    fn block_expression(&mut self) -> Result<Expr, Box<dyn Error>> {
+        let start = self.previous().offset;
+        let mut stmts = vec![];
+        self.enter()?;
+        self.depth += 1;
+
+        let value = loop {
+            if self.match_token(RightBrace) {
+                break Expr::Literal {
+                    literal: LiteralValue::Nil,
+                    span: token_span(self.previous()),
+                };
+            }
+            match self.peek().token_type {
+                Var | Lazy | Class | Func | Print | LeftBrace | Scope | If | While | For | Return | Eof => {
+                    stmts.push(Box::from(self.declaration()?));
+                }
+                _ => {
+                    let expr = self.expression()?;
+                    if self.match_token(Semicolon) {
+                        stmts.push(Box::from(Stmt::Expression {
+                            span: self.span_from(expr.span().0),
+                            expression: expr,
+                        }));
+                    } else {
+                        self.consume(TokenType::RightBrace, "Expect '}' after block value.")?;
+                        break expr;
+                    }
+                }
+            }
+        };
+
+        self.depth -= 1;
+        self.nesting -= 1;
+        Ok(Expr::Block {
+            stmts,
+            value: Box::from(value),
+            span: self.span_from(start),
+        })
+    }
+
     fn function_expression(&mut self) -> Result<Expr, Box<dyn Error>> {
+        let start = self.previous().offset;
         // Check for the (
         let paren = self.consume(
             TokenType::LeftParen,
@@ -565,7 +984,7 @@ impl Parser {
         // The body of the function which is basically a block
         // Will return a array of statements
         let body = match self.block()? {
-            Stmt::Block { stmts } => stmts,
+            Stmt::Block { stmts, span: _ } => stmts,
             _ => panic!("Block statement parsed something that was not a block"),
         };
 
@@ -574,19 +993,58 @@ impl Parser {
             paren,
             args: params,
             body,
+            span: self.span_from(start),
         })
     }
 
     // consume the given token or return a error if the token does not match the expected one
+    // The error names the token that was found instead
+    // The ';' ending an expression or print statement, the last statement of the source may leave
+    // it out as scripts often forget it
+    fn end_statement(&mut self, msg: &str) -> Result<(), Box<dyn Error>> {
+        if self.depth == 0 && self.is_at_end() {
+            return Ok(());
+        }
+        self.consume(TokenType::Semicolon, msg)?;
+        Ok(())
+    }
+
     fn consume(&mut self, token_type: TokenType, msg: &str) -> Result<Token, Box<dyn Error>> {
         let token = self.peek();
         if token.token_type == token_type {
             self.advance();
             let token = self.previous();
             Ok(token.clone())
+        } else if token.token_type == Eof {
+            Err(format!("Line {}: {} but found end of file", token.line_number, msg).into())
         } else {
-            Err(format!("Line {}: {}", token.line_number, msg).into())
+            Err(format!(
+                "Line {}: {} but found '{}'",
+                token.line_number, msg, token.lexeme
+            )
+            .into())
+        }
+    }
+
+    // Go one level deeper in the recursion, erroring if it gets too deep
+    fn enter(&mut self) -> Result<(), Box<dyn Error>> {
+        self.nesting += 1;
+        if self.nesting > self.max_nesting {
+            let max_nesting = self.max_nesting;
+            return Err(format!(
+                "Line {}: Nesting is deeper than {} levels",
+                self.peek().line_number,
+                max_nesting
+            )
+            .into());
         }
+        Ok(())
+    }
+
+    // Span from the byte offset start to the end of the last consumed token
+    fn span_from(&mut self, start: usize) -> Span {
+        let end = self.previous();
+        (start, end.offset + end.length)
     }
 
     // Return a previous token
@@ -644,19 +1102,29 @@ impl Parser {
     }
 
     // Sync up to the code if we hit a error
+    // If the error happened inside a block we skip to the '}' closing it so the error stays local
+    // to that block, otherwise we stop at the next statement keyword
     fn synchronize(&mut self) {
-        self.advance();
-
         while !self.is_at_end() {
-            if self.peek().token_type == Eof {
-                return;
-            }
-            match self.peek().token_type {
-                Class | Func | Var | For | If | While | Print | Return => return,
+            match self.advance().token_type {
+                LeftBrace => self.depth += 1,
+                RightBrace => {
+                    if self.depth <= 1 {
+                        self.depth = 0;
+                        return;
+                    }
+                    self.depth -= 1;
+                }
                 _ => (),
             }
-            self.advance();
+            if self.depth == 0 {
+                match self.peek().token_type {
+                    Class | Func | Var | Lazy | For | If | While | Print | Return | Scope => return,
+                    _ => (),
+                }
+            }
         }
+        self.depth = 0;
     }
 }
 
@@ -708,6 +1176,319 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_postfix() -> Result<(), Box<dyn Error>> {
+        let source = "-3! != !a;";
+        let mut scanner = Scanner::new(source);
+        let tokens = scanner.scan_tokens().unwrap();
+
+        let mut parser = Parser::new(tokens);
+        let parsed_expr = parser.parse()?;
+
+        match &parsed_expr[0] {
+            Stmt::Expression {
+                expression,
+                span: _,
+            } => assert_eq!(expression.to_string(), "(!= (- (3 !)) (! (var a)))"),
+            _ => panic!("Expected an expression"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_logical_chain() -> Result<(), Box<dyn Error>> {
+        let source = "a or b or c and d and e;";
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let parsed = Parser::new(tokens).parse()?;
+
+        match &parsed[0] {
+            Stmt::Expression {
+                expression,
+                span: _,
+            } => assert_eq!(
+                expression.to_string(),
+                "(Or (Or (var a) (var b)) (And (And (var c) (var d)) (var e)))"
+            ),
+            _ => panic!("Expected an expression"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_and_binds_tighter_than_or() -> Result<(), Box<dyn Error>> {
+        let source = "a and b or c; a or b and c; a == b and c or !d;";
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let parsed = Parser::new(tokens).parse()?;
+
+        let exprs: Vec<String> = parsed.iter().map(|stmt| stmt.to_string()).collect();
+        assert_eq!(
+            exprs,
+            vec![
+                "(Or (And (var a) (var b)) (var c))",
+                "(Or (var a) (And (var b) (var c)))",
+                "(Or (And (== (var a) (var b)) (var c)) (! (var d)))"
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_anon_func() -> Result<(), Box<dyn Error>> {
+        let source = "var sq = func(x) { return x * x; };";
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let parsed = Parser::new(tokens).parse()?;
+
+        match &parsed[0] {
+            Stmt::Var {
+                name: _,
+                initializer:
+                    Expr::AnonFunc {
+                        paren: _,
+                        args,
+                        body,
+                        span,
+                    },
+                type_name: _,
+                lazy: _,
+                span: _,
+            } => {
+                assert_eq!(args.len(), 1);
+                assert_eq!(args[0].lexeme, "x");
+                assert_eq!(body.len(), 1);
+                assert_eq!(&source[span.0..span.1], "func(x) { return x * x; }");
+            }
+            _ => panic!("Expected a var holding an anonymous function"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_return() -> Result<(), Box<dyn Error>> {
+        let source = "func f(a) { if (a) return; return a + 1; }";
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let parsed = Parser::new(tokens).parse()?;
+
+        let body = match &parsed[0] {
+            Stmt::Function {
+                name: _,
+                params: _,
+                body,
+                pure: _,
+                span: _,
+            } => body,
+            _ => panic!("Expected a function"),
+        };
+        match body[0].as_ref() {
+            Stmt::IfElse {
+                predicate: _,
+                then_branch,
+                else_branch: _,
+                span: _,
+            } => match then_branch.as_ref() {
+                Stmt::Return {
+                    keyword,
+                    value,
+                    span: _,
+                } => {
+                    assert_eq!(keyword.lexeme, "return");
+                    assert!(value.is_none());
+                }
+                _ => panic!("Expected a bare return"),
+            },
+            _ => panic!("Expected an if"),
+        }
+        match body[1].as_ref() {
+            Stmt::Return {
+                keyword: _,
+                value: Some(value),
+                span: _,
+            } => assert_eq!(value.to_string(), "(+ (var a) 1)"),
+            _ => panic!("Expected a return with a value"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_class() -> Result<(), Box<dyn Error>> {
+        let source =
+            "class A { n = 1; init(x) { this.x = x; } get() { return this.x; } }\nA(1).get();";
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let parsed = Parser::new(tokens).parse()?;
+
+        assert_eq!(
+            parsed[0].to_string(),
+            "(class A (field n 1) (func init (x)) (func get ()))"
+        );
+        match &parsed[0] {
+            Stmt::Class {
+                name: _,
+                fields: _,
+                methods,
+                span: _,
+            } => match &methods[0] {
+                Stmt::Function {
+                    name: _,
+                    params: _,
+                    body,
+                    pure: _,
+                    span: _,
+                } => assert_eq!(body[0].to_string(), "(set this x (var x))"),
+                _ => panic!("Expected a method"),
+            },
+            _ => panic!("Expected a class"),
+        }
+        assert_eq!(parsed[1].to_string(), "<fn (get <fn (var A)> [1] get)> []");
+        Ok(())
+    }
+
+    #[test]
+    fn test_power_is_right_associative() -> Result<(), Box<dyn Error>> {
+        let source = "2 ** 3 ** 2; -2 ** 2; 2 * 3 ** 2;";
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let parsed = Parser::new(tokens).parse()?;
+
+        let exprs: Vec<String> = parsed.iter().map(|stmt| stmt.to_string()).collect();
+        assert_eq!(
+            exprs,
+            vec!["(** 2 (** 3 2))", "(- (** 2 2))", "(* 2 (** 3 2))"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_ternary_is_right_associative() -> Result<(), Box<dyn Error>> {
+        let source = "a ? b : c ? d : e; x = a or b ? 1 : 2;";
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let parsed = Parser::new(tokens).parse()?;
+
+        let exprs: Vec<String> = parsed.iter().map(|stmt| stmt.to_string()).collect();
+        assert_eq!(
+            exprs,
+            vec![
+                "(? (var a) (var b) (? (var c) (var d) (var e)))",
+                "(assign x (? (Or (var a) (var b)) 1 2))"
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_semicolon_at_end() {
+        let parse = |source: &str| Parser::new(Scanner::new(source).scan_tokens().unwrap()).parse();
+        assert_eq!(parse("var a = 1;\na + 1").unwrap().len(), 2);
+        assert!(parse("print 1").is_ok());
+        // Only the last statement may leave it out
+        assert!(parse("a + 1\nprint a;").is_err());
+        assert!(parse("{ a + 1; print a }").is_err());
+    }
+
+    #[test]
+    fn test_print_function() -> Result<(), Box<dyn Error>> {
+        let source = "print(1);\nprint 1 + 2;\nprint (1) + 2;";
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let parsed = Parser::new(tokens).with_print_function().parse()?;
+
+        let calls: Vec<String> = parsed
+            .iter()
+            .map(|stmt| match stmt {
+                Stmt::Expression {
+                    expression,
+                    span: _,
+                } => expression.to_string(),
+                _ => panic!("Expected print to be an expression"),
+            })
+            .collect();
+        assert!(calls[0].starts_with("<fn (var print)>"));
+        assert!(calls[1].starts_with("<fn (var print)>"));
+        assert!(calls[1].contains("(+ 1 2)"));
+        assert!(calls[2].starts_with("(+ <fn (var print)>"));
+
+        // Without the mode print is still a statement
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let parsed = Parser::new(tokens).parse()?;
+        assert!(parsed.iter().all(|stmt| matches!(
+            stmt,
+            Stmt::Print {
+                expression: _,
+                span: _
+            }
+        )));
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_on_block_end() -> Result<(), Box<dyn Error>> {
+        let source = "{ var = 1; print 1; }\nprint 2;\nx = 3;";
+        let mut scanner = Scanner::new(source);
+        let tokens = scanner.scan_tokens().unwrap();
+
+        let mut parser = Parser::new(tokens);
+        let err = parser.parse().unwrap_err().to_string();
+
+        assert_eq!(err.lines().count(), 1);
+        assert!(err.starts_with("Line 1"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_error_reports_found_lexeme() -> Result<(), Box<dyn Error>> {
+        let source = "var a = 1;\n\n\nvar b = 2\nprint a;";
+        let mut scanner = Scanner::new(source);
+        let tokens = scanner.scan_tokens().unwrap();
+
+        let mut parser = Parser::new(tokens);
+        let err = parser.parse().unwrap_err().to_string();
+
+        assert_eq!(
+            err,
+            "Line 5: Expected ';' after variable declaration but found 'print'\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_nesting() -> Result<(), Box<dyn Error>> {
+        let source = "(".repeat(100_000) + "1" + &")".repeat(100_000) + ";";
+        let mut scanner = Scanner::new(&source);
+        let tokens = scanner.scan_tokens()?;
+
+        let mut parser = Parser::new(tokens);
+        let err = parser.parse().unwrap_err().to_string();
+        assert_eq!(err, "Line 1: Nesting is deeper than 64 levels\n");
+
+        let source = "{".repeat(20) + &"}".repeat(20);
+        let mut scanner = Scanner::new(&source);
+        let tokens = scanner.scan_tokens()?;
+
+        let mut parser = Parser::new(tokens).with_max_nesting(10);
+        assert!(parser.parse().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_nesting_statements() -> Result<(), Box<dyn Error>> {
+        let sources = [
+            "if (true) ".repeat(100_000) + "print 1;",
+            "while (false) ".repeat(100_000) + "print 1;",
+            "for (;;) ".repeat(100_000) + "print 1;",
+            "a = ".repeat(100_000) + "1;",
+            "true ? 1 : ".repeat(100_000) + "1;",
+        ];
+        for source in sources {
+            let tokens = Scanner::new(&source).scan_tokens()?;
+            let err = Parser::new(tokens).parse().unwrap_err().to_string();
+            assert!(
+                err.starts_with("Line 1: Nesting is deeper than 64 levels\n"),
+                "{}",
+                &err[..err.len().min(200)]
+            );
+        }
+
+        let tokens = Scanner::new("if (a) while (b) a = b = 1;").scan_tokens()?;
+        assert!(Parser::new(tokens).parse().is_ok());
+        Ok(())
+    }
+
     #[test]
     fn test_eq_with_paren() -> Result<(), Box<dyn Error>> {
         let source = "1 == (2+2);";
@@ -721,4 +1502,87 @@ mod tests {
         //assert_eq!(string_expr, "(== 1 (group (+ 2 2)))");
         Ok(())
     }
+
+    #[test]
+    fn test_type_annotations() -> Result<(), Box<dyn Error>> {
+        let tokens = Scanner::new("var a: Number = 1; var b = 2;").scan_tokens()?;
+        let stmts = Parser::new(tokens).parse()?;
+        let types: Vec<Option<String>> = stmts
+            .iter()
+            .map(|stmt| match stmt {
+                Stmt::Var {
+                    name: _,
+                    initializer: _,
+                    type_name,
+                    lazy: _,
+                    span: _,
+                } => type_name.as_ref().map(|t| t.lexeme.clone()),
+                _ => panic!("Expected a var"),
+            })
+            .collect();
+        assert_eq!(types, vec![Some("Number".to_string()), None]);
+
+        let tokens = Scanner::new("var a: Int = 1;").scan_tokens()?;
+        let err = Parser::new(tokens).parse().unwrap_err();
+        assert_eq!(err.to_string(), "Line 1: Unknown type 'Int'\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_attributes() -> Result<(), Box<dyn Error>> {
+        let tokens = Scanner::new("@pure func f(x) { return x; }").scan_tokens()?;
+        let stmts = Parser::new(tokens).parse()?;
+        match &stmts[0] {
+            Stmt::Function {
+                name,
+                params: _,
+                body: _,
+                pure,
+                span,
+            } => {
+                assert_eq!(name.lexeme, "f");
+                assert!(pure);
+                assert_eq!(span.0, 0);
+            }
+            _ => panic!("Expected a function"),
+        }
+
+        let tokens = Scanner::new("@fast func f() {}").scan_tokens()?;
+        let err = Parser::new(tokens).parse().unwrap_err();
+        assert_eq!(err.to_string(), "Line 1: Unknown attribute '@fast'\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_spans() -> Result<(), Box<dyn Error>> {
+        let source = "var a = 1;\nprint (a + 2) * foo(3);";
+        let mut scanner = Scanner::new(source);
+        let tokens = scanner.scan_tokens().unwrap();
+
+        let mut parser = Parser::new(tokens);
+        let stmts = parser.parse()?;
+
+        let span_text = |span: Span| &source[span.0..span.1];
+        assert_eq!(span_text(stmts[0].span()), "var a = 1;");
+        assert_eq!(span_text(stmts[1].span()), "print (a + 2) * foo(3);");
+        match &stmts[1] {
+            Stmt::Print {
+                expression:
+                    Expr::Binary {
+                        left,
+                        operator: _,
+                        right,
+                        span,
+                    },
+                span: _,
+            } => {
+                // The binary covers both operands
+                assert_eq!(span_text(*span), "(a + 2) * foo(3)");
+                assert_eq!(span_text(left.span()), "(a + 2)");
+                assert_eq!(span_text(right.span()), "foo(3)");
+            }
+            _ => panic!("Expected a print of a binary expression"),
+        }
+        Ok(())
+    }
 }
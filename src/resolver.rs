@@ -1,6 +1,14 @@
-use std::{collections::HashMap, error::Error};
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+};
 
-use crate::{expr::Expr, interpreter::Interpreter, stmt::Stmt, Token};
+use crate::{
+    expr::{Expr, LiteralValue, Span},
+    interpreter::Interpreter,
+    stmt::Stmt,
+    Token,
+};
 
 use std::cell::RefCell;
 use std::rc::Rc;
@@ -8,6 +16,42 @@ use std::rc::Rc;
 pub struct Resolver {
     pub interpreter: Rc<RefCell<Interpreter>>,
     scopes: Vec<HashMap<String, bool>>,
+    // Names in each scope that are function parameters, kept in step with scopes
+    params: Vec<HashSet<String>>,
+    // Suspicious code that is still valid and where it is, reported by --check
+    pub warnings: Vec<(Span, String)>,
+    // Name of the @pure function being resolved and the index of its scope
+    pure_function: Option<(String, usize)>,
+    // How many class bodies we are in, 'this' is only allowed inside one
+    class_depth: usize,
+    // How many function bodies we are in, 'return' is only allowed inside one
+    function_depth: usize,
+}
+
+// Whether a return can be reached from the statement, returns inside nested functions do not
+// count as they do not leave the current one
+fn can_return(stmt: &Stmt) -> bool {
+    match stmt {
+        Stmt::Return {
+            keyword: _,
+            value: _,
+            span: _,
+        } => true,
+        Stmt::Block { stmts, span: _ } => stmts.iter().any(|stmt| can_return(stmt)),
+        Stmt::IfElse {
+            predicate: _,
+            then_branch,
+            else_branch,
+            span: _,
+        } => can_return(then_branch) || else_branch.as_ref().is_some_and(|els| can_return(els)),
+        Stmt::WhileLoop {
+            cond: _,
+            body,
+            else_branch,
+            span: _,
+        } => can_return(body) || else_branch.as_ref().is_some_and(|els| can_return(els)),
+        _ => false,
+    }
 }
 
 #[allow(dead_code)]
@@ -16,17 +60,25 @@ impl Resolver {
         Resolver {
             interpreter,
             scopes: vec![],
+            params: vec![],
+            warnings: vec![],
+            pure_function: None,
+            class_depth: 0,
+            function_depth: 0,
         }
     }
 
     fn resolve(&mut self, stmt: &Stmt) -> Result<(), Box<dyn Error>> {
         match stmt {
-            Stmt::Block { stmts: _ } => {
+            Stmt::Block { stmts: _, span: _ } => {
                 self.resolve_block(stmt)?;
             }
             Stmt::Var {
                 name: _,
                 initializer: _,
+                type_name: _,
+                lazy: _,
+                span: _,
             } => {
                 self.resolve_var(stmt)?;
             }
@@ -34,30 +86,116 @@ impl Resolver {
                 name: _,
                 params: _,
                 body: _,
+                pure: _,
+                span: _,
             } => {
                 self.resolve_function(stmt)?;
             }
-            Stmt::Expression { expression } => {
+            Stmt::Class {
+                name,
+                fields,
+                methods,
+                span: _,
+            } => {
+                self.declare(name)?;
+                self.define(name)?;
+                // Field defaults and methods close over a scope holding 'this'
+                self.begin_scope()?;
+                self.scopes
+                    .last_mut()
+                    .expect("No scope found while defining this")
+                    .insert("this".to_string(), true);
+                self.class_depth += 1;
+                let res = self.resolve_class_body(fields, methods);
+                self.class_depth -= 1;
+                self.end_scope()?;
+                res?;
+            }
+            Stmt::Expression {
+                expression,
+                span: _,
+            } => {
                 self.resolve_expr(expression)?;
             }
             Stmt::IfElse {
                 predicate: _,
                 then_branch: _,
                 else_branch: _,
+                span: _,
             } => {
                 self.resolve_if_else(stmt)?;
             }
-            Stmt::Print { expression } => {
+            Stmt::Print {
+                expression,
+                span: _,
+            } => {
+                if let Some((name, _)) = &self.pure_function {
+                    return Err(format!("Pure function '{}' cannot print", name).into());
+                }
                 self.resolve_expr(expression)?;
             }
-            Stmt::Return { keyword: _, value } => {
+            Stmt::Return {
+                keyword,
+                value,
+                span: _,
+            } => {
+                if self.function_depth == 0 {
+                    return Err(format!(
+                        "Line {}: Cannot return from top-level code",
+                        keyword.line_number
+                    )
+                    .into());
+                }
                 if let Some(val) = value {
                     self.resolve_expr(val)?;
                 }
             }
-            Stmt::WhileLoop { cond, body } => {
+            Stmt::WhileLoop {
+                cond,
+                body,
+                else_branch,
+                span: _,
+            } => {
+                // There is no break so a while (true) can only be left by returning
+                if let Expr::Literal {
+                    literal: LiteralValue::Bool(true),
+                    span: _,
+                } = cond
+                {
+                    if !can_return(body) {
+                        self.warnings
+                            .push((stmt.span(), "while (true) loop never ends".to_string()));
+                    }
+                }
+                self.check_assign_cond(cond);
                 self.resolve_expr(cond)?;
                 self.resolve(body)?;
+                if let Some(els) = else_branch {
+                    self.resolve(els.as_ref())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn resolve_class_body(
+        &mut self,
+        fields: &Vec<(Token, Expr)>,
+        methods: &Vec<Stmt>,
+    ) -> Result<(), Box<dyn Error>> {
+        for (_, value) in fields {
+            self.resolve_expr(value)?;
+        }
+        for method in methods {
+            if let Stmt::Function {
+                name: _,
+                params,
+                body,
+                pure: _,
+                span: _,
+            } = method
+            {
+                self.resolve_function_helper(params, &body.iter().map(|b| b.as_ref()).collect())?;
             }
         }
         Ok(())
@@ -69,7 +207,9 @@ impl Resolver {
                 predicate,
                 then_branch,
                 else_branch,
+                span: _,
             } => {
+                self.check_assign_cond(predicate);
                 self.resolve_expr(predicate)?;
                 self.resolve(then_branch)?;
                 if let Some(els) = else_branch {
@@ -83,10 +223,23 @@ impl Resolver {
 
     fn resolve_function(&mut self, stmt: &Stmt) -> Result<(), Box<dyn Error>> {
         match stmt {
-            Stmt::Function { name, params, body } => {
+            Stmt::Function {
+                name,
+                params,
+                body,
+                pure,
+                span: _,
+            } => {
                 self.declare(name)?;
                 self.define(name)?;
-                self.resolve_function_helper(params, &body.iter().map(|b| b.as_ref()).collect())?;
+                let enclosing_pure = self.pure_function.clone();
+                if *pure {
+                    self.pure_function = Some((name.lexeme.clone(), self.scopes.len()));
+                }
+                let res = self
+                    .resolve_function_helper(params, &body.iter().map(|b| b.as_ref()).collect());
+                self.pure_function = enclosing_pure;
+                res?;
             }
             _ => panic!("Wrong type in resolve function"),
         }
@@ -103,8 +256,15 @@ impl Resolver {
         for param in params {
             self.declare(param)?;
             self.define(param)?;
+            self.params
+                .last_mut()
+                .expect("No scope found while defining params")
+                .insert(param.lexeme.clone());
         }
-        self.resolve_many(body)?;
+        self.function_depth += 1;
+        let res = self.resolve_many(body);
+        self.function_depth -= 1;
+        res?;
         self.end_scope()?;
         Ok(())
     }
@@ -119,8 +279,18 @@ impl Resolver {
 
     fn resolve_var(&mut self, stmt: &Stmt) -> Result<(), Box<dyn Error>> {
         match stmt {
-            Stmt::Var { name, initializer } => {
+            Stmt::Var {
+                name,
+                initializer,
+                type_name: _,
+                lazy: _,
+                span: _,
+            } => {
                 self.declare(name)?;
+                // A function stored in a variable may refer to itself for recursion
+                if let Expr::AnonFunc { .. } = initializer {
+                    self.define(name)?;
+                }
                 self.resolve_expr(initializer)?;
                 self.define(name)?;
                 Ok(())
@@ -137,6 +307,11 @@ impl Resolver {
             .last_mut()
             .expect("No scope found while declare")
             .insert(name.lexeme.clone(), false);
+        // A var with the same name as a parameter is a new local
+        self.params
+            .last_mut()
+            .expect("No scope found while declare")
+            .remove(&name.lexeme);
         Ok(())
     }
 
@@ -154,7 +329,7 @@ impl Resolver {
     #[allow(clippy::vec_box)]
     fn resolve_block(&mut self, stmt: &Stmt) -> Result<(), Box<dyn Error>> {
         match stmt {
-            Stmt::Block { stmts } => {
+            Stmt::Block { stmts, span: _ } => {
                 self.begin_scope()?;
                 self.resolve_many(&stmts.iter().map(|b| b.as_ref()).collect())?;
                 self.end_scope()?;
@@ -166,26 +341,38 @@ impl Resolver {
 
     fn begin_scope(&mut self) -> Result<(), Box<dyn Error>> {
         self.scopes.push(HashMap::new());
+        self.params.push(HashSet::new());
         Ok(())
     }
 
     fn end_scope(&mut self) -> Result<(), Box<dyn Error>> {
         self.scopes.pop().expect("Stack underflow during scope");
+        self.params.pop();
         Ok(())
     }
 
     fn resolve_expr(&mut self, expr: &Expr) -> Result<(), Box<dyn Error>> {
         match expr {
-            Expr::Variable { name: _ } => {
+            Expr::Variable {
+                id: _,
+                name: _,
+                span: _,
+            } => {
                 self.resolve_expr_var(expr)?;
             }
-            Expr::Assign { name: _, value: _ } => {
+            Expr::Assign {
+                id: _,
+                name: _,
+                value: _,
+                span: _,
+            } => {
                 self.resolve_expr_assign(expr)?;
             }
             Expr::Binary {
                 left,
                 operator: _,
                 right,
+                span: _,
             } => {
                 self.resolve_expr(left)?;
                 self.resolve_expr(right)?;
@@ -194,43 +381,120 @@ impl Resolver {
                 callee,
                 paren: _,
                 args,
+                span: _,
             } => {
                 self.resolve_expr(callee)?;
                 for arg in args {
                     self.resolve_expr(arg)?;
                 }
             }
-            Expr::Grouping { expression } => {
+            Expr::Grouping {
+                expression,
+                span: _,
+            } => {
                 self.resolve_expr(expression)?;
             }
-            Expr::Literal { literal: _ } => {}
+            Expr::Literal {
+                literal: _,
+                span: _,
+            } => {}
             Expr::Logical {
                 left,
                 operator: _,
                 right,
+                span: _,
             } => {
                 self.resolve_expr(left)?;
                 self.resolve_expr(right)?;
             }
-            Expr::Unary { operator: _, right } => {
+            Expr::Unary {
+                operator: _,
+                right,
+                span: _,
+            } => {
                 self.resolve_expr(right)?;
             }
+            Expr::Postfix {
+                operator: _,
+                operand,
+                span: _,
+            } => {
+                self.resolve_expr(operand)?;
+            }
             Expr::AnonFunc {
                 paren: _,
                 args,
                 body,
+                span: _,
             } => {
                 self.resolve_function_helper(args, &body.iter().map(|b| b.as_ref()).collect())?;
             }
+            Expr::Block {
+                stmts,
+                value,
+                span: _,
+            } => {
+                self.begin_scope()?;
+                self.resolve_many(&stmts.iter().map(|b| b.as_ref()).collect())?;
+                self.resolve_expr(value)?;
+                self.end_scope()?;
+            }
+            Expr::Ternary {
+                cond,
+                then_branch,
+                else_branch,
+                span: _,
+            } => {
+                self.resolve_expr(cond)?;
+                self.resolve_expr(then_branch)?;
+                self.resolve_expr(else_branch)?;
+            }
+            Expr::Get {
+                object,
+                name: _,
+                span: _,
+            } => {
+                self.resolve_expr(object)?;
+            }
+            Expr::Set {
+                object,
+                name: _,
+                value,
+                span: _,
+            } => {
+                self.resolve_expr(value)?;
+                self.resolve_expr(object)?;
+            }
+            Expr::This {
+                id,
+                keyword,
+                span: _,
+            } => {
+                if self.class_depth == 0 {
+                    return Err(format!(
+                        "Line {}: Cannot use 'this' outside of a class",
+                        keyword.line_number
+                    )
+                    .into());
+                }
+                self.resolve_local(*id, keyword)?;
+            }
         }
         Ok(())
     }
 
     fn resolve_expr_assign(&mut self, expr: &Expr) -> Result<(), Box<dyn Error>> {
         match expr {
-            Expr::Assign { name, value } => {
+            Expr::Assign {
+                id,
+                name,
+                value,
+                span: _,
+            } => {
                 self.resolve_expr(value.as_ref())?;
-                self.resolve_local(expr, name)?;
+                self.check_pure(name)?;
+                self.check_param_assign(expr, name);
+                self.resolve_local(*id, name)?;
             }
             _ => panic!("Wrong type in resolve assign"),
         }
@@ -239,38 +503,337 @@ impl Resolver {
 
     fn resolve_expr_var(&mut self, expr: &Expr) -> Result<(), Box<dyn Error>> {
         match expr {
-            Expr::Variable { name } => {
+            Expr::Variable { id, name, span: _ } => {
                 if !self.scopes.is_empty()
-                    && !(*self
+                    && self
                         .scopes
                         .last()
                         .expect("No scopes during var expr")
                         .get(&name.lexeme)
-                        .unwrap())
+                        == Some(&false)
                 {
                     return Err("Cannot read local variable in its own initialization".into());
                 }
-                self.resolve_local(expr, name)?;
+                self.check_pure(name)?;
+                self.resolve_local(*id, name)?;
             }
             _ => panic!("Wrong type in resolve var"),
         }
         Ok(())
     }
 
-    fn resolve_local(&mut self, expr: &Expr, name: &Token) -> Result<(), Box<dyn Error>> {
+    // Inside a @pure function only names declared in it and the function itself can be used
+    fn check_pure(&self, name: &Token) -> Result<(), Box<dyn Error>> {
+        if let Some((function, base)) = &self.pure_function {
+            if name.lexeme != *function
+                && !self.scopes[*base..]
+                    .iter()
+                    .any(|scope| scope.contains_key(&name.lexeme))
+            {
+                return Err(format!(
+                    "Line {}: Pure function '{}' cannot use '{}' from outside of it",
+                    name.line_number, function, name.lexeme
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    // Warn about 'if (x = 5)' which is most likely meant to be a comparison
+    // Wrapping it in another pair of parens, 'if ((x = 5))', says the assignment is on purpose
+    fn check_assign_cond(&mut self, cond: &Expr) {
+        if let Expr::Assign {
+            id: _,
+            name,
+            value: _,
+            span,
+        } = cond
+        {
+            self.warnings.push((
+                *span,
+                format!(
+                    "Assignment to '{}' used as a condition, did you mean '=='?",
+                    name.lexeme
+                ),
+            ));
+        }
+    }
+
+    // Warn when the closest binding of name is a parameter, '_' parameters are meant to be
+    // changed
+    fn check_param_assign(&mut self, expr: &Expr, name: &Token) {
+        if name.lexeme.starts_with('_') {
+            return;
+        }
+        let closest = self
+            .scopes
+            .iter()
+            .zip(&self.params)
+            .rev()
+            .find(|(scope, _)| scope.contains_key(&name.lexeme));
+        if let Some((_, params)) = closest {
+            if params.contains(&name.lexeme) {
+                self.warnings.push((
+                    expr.span(),
+                    format!("Assignment to parameter '{}'", name.lexeme),
+                ));
+            }
+        }
+    }
+
+    // Record how many scopes up from the innermost one name is declared
+    // Code run at a breakpoint starts inside a local scope, names from there are found in the
+    // Environment of the Interpreter
+    // Names not in any scope are left alone, they are globals
+    fn resolve_local(&mut self, id: usize, name: &Token) -> Result<(), Box<dyn Error>> {
         let size = self.scopes.len();
-        if size==0 {
-            return Ok(());
+        for i in (0..size).rev() {
+            if self.scopes[i].contains_key(&name.lexeme) {
+                self.interpreter.borrow_mut().resolve(id, size - 1 - i)?;
+                return Ok(());
+            }
+        }
+        let depth = self
+            .interpreter
+            .borrow()
+            .environments
+            .borrow()
+            .depth_of(&name.lexeme);
+        if let Some(depth) = depth {
+            self.interpreter.borrow_mut().resolve(id, size + depth)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Parser, Scanner};
+
+    fn resolve_source(source: &str) -> Result<(), Box<dyn Error>> {
+        let tokens = Scanner::new(source).scan_tokens()?;
+        let stmts = Parser::new(tokens).parse()?;
+        let interpreter = Rc::new(RefCell::new(Interpreter::new()));
+        let mut resolver = Resolver::new(interpreter);
+        resolver.resolve_many(&stmts.iter().collect())
+    }
+
+    #[test]
+    fn recursive_anon_func_in_var() -> Result<(), Box<dyn Error>> {
+        resolve_source(
+            "{ var fact = func(n) { if (n <= 1) return 1; return n * fact(n - 1); }; }",
+        )
+    }
+
+    #[test]
+    fn shadowed_variables_resolve_to_the_closest_scope() {
+        let source = "var a = 1; { var a = 2; { var b = a; { a = b; } } } a;";
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let stmts = Parser::new(tokens).parse().unwrap();
+        let interpreter = Rc::new(RefCell::new(Interpreter::new()));
+        let mut resolver = Resolver::new(interpreter.clone());
+        resolver.resolve_many(&stmts.iter().collect()).unwrap();
+
+        // Distances in the order the names appear in the source
+        let mut distances = vec![];
+        fn collect(expr: &Expr, interpreter: &Interpreter, out: &mut Vec<Option<usize>>) {
+            match expr {
+                Expr::Variable {
+                    id,
+                    name: _,
+                    span: _,
+                } => out.push(interpreter.get_distance(*id)),
+                Expr::Assign {
+                    id,
+                    name: _,
+                    value,
+                    span: _,
+                } => {
+                    out.push(interpreter.get_distance(*id));
+                    collect(value, interpreter, out);
+                }
+                _ => {}
+            }
         }
-        if size != 0 {
-            for i in (0..(size - 1)).rev() {
-                if self.scopes[i].contains_key(&name.lexeme) {
-                    self.interpreter.borrow_mut().resolve(expr, size - 1 - i)?;
-                    // (self.resolve_impl)(expr,size-1-i)?;
-                    return Ok(());
+        fn walk(stmt: &Stmt, interpreter: &Interpreter, out: &mut Vec<Option<usize>>) {
+            match stmt {
+                Stmt::Block { stmts, span: _ } => {
+                    stmts.iter().for_each(|stmt| walk(stmt, interpreter, out))
                 }
+                Stmt::Var {
+                    name: _,
+                    initializer,
+                    type_name: _,
+                    lazy: _,
+                    span: _,
+                } => collect(initializer, interpreter, out),
+                Stmt::Expression {
+                    expression,
+                    span: _,
+                } => collect(expression, interpreter, out),
+                _ => {}
             }
         }
-        Ok(())
+        for stmt in &stmts {
+            walk(stmt, &interpreter.borrow(), &mut distances);
+        }
+        // 'var b = a' sees the a one block up, 'a = b' assigns two up and reads b one up, the
+        // last a is the global
+        assert_eq!(distances, vec![Some(1), Some(2), Some(1), None]);
+    }
+
+    fn warnings_for(source: &str) -> Vec<(Span, String)> {
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let stmts = Parser::new(tokens).parse().unwrap();
+        let interpreter = Rc::new(RefCell::new(Interpreter::new()));
+        let mut resolver = Resolver::new(interpreter);
+        resolver.resolve_many(&stmts.iter().collect()).unwrap();
+        resolver.warnings
+    }
+
+    #[test]
+    fn infinite_while_warns() {
+        let source = "var a = 1;\nwhile (true) {\n print 1;\n}";
+        let warnings = warnings_for(source);
+        assert_eq!(warnings.len(), 1);
+        let (span, msg) = &warnings[0];
+        assert_eq!(&source[span.0..span.1], "while (true) {\n print 1;\n}");
+        assert_eq!(msg, "while (true) loop never ends");
+        // Returning from inside the loop is a way out
+        assert!(warnings_for("func f() { while (true) { if (1) return 1; } }").is_empty());
+        // A return in a nested function does not leave the loop
+        assert_eq!(
+            warnings_for("while (true) { var f = func () { return 1; }; }").len(),
+            1
+        );
+        assert!(warnings_for("var a = 1; while (a) { a = 0; }").is_empty());
+    }
+
+    #[test]
+    fn assign_in_condition_warns() {
+        let source = "var x = 1;\nif (x = 5) print x;";
+        let warnings = warnings_for(source);
+        assert_eq!(warnings.len(), 1);
+        let (span, msg) = &warnings[0];
+        assert_eq!(&source[span.0..span.1], "x = 5");
+        assert_eq!(msg, "Assignment to 'x' used as a condition, did you mean '=='?");
+        assert_eq!(warnings_for("var x = 1; while (x = nil) print x;").len(), 1);
+        assert!(warnings_for("var x = 1; if (x == 5) print x;").is_empty());
+        // Doubled parens opt out
+        assert!(warnings_for("var x = 1; if ((x = 5)) print x;").is_empty());
+    }
+
+    #[test]
+    fn param_assign_warns() {
+        let source = "func f(a, _b) {\n  var c = 1;\n  c = 2;\n  _b = 3;\n  { a = 4; }\n}";
+        let warnings = warnings_for(source);
+        assert_eq!(warnings.len(), 1);
+        let (span, msg) = &warnings[0];
+        assert_eq!(&source[span.0..span.1], "a = 4");
+        assert_eq!(msg, "Assignment to parameter 'a'");
+        // Shadowed by a local
+        assert!(warnings_for("func f(a) { { var a = 1; a = 2; } }").is_empty());
+        assert_eq!(warnings_for("var g = func (x) { x = x + 1; };").len(), 1);
+    }
+
+    #[test]
+    fn pure_functions() {
+        assert!(resolve_source(
+            "@pure func fib(n) { if (n <= 1) return n; var a = fib(n - 1); return a + fib(n - 2); }"
+        )
+        .is_ok());
+        assert!(resolve_source("@pure func f(x) { var g = func (y) { return x + y; }; return g; }")
+            .is_ok());
+        assert_eq!(
+            resolve_source("@pure func f(x) { print x; }")
+                .unwrap_err()
+                .to_string(),
+            "Pure function 'f' cannot print"
+        );
+        assert_eq!(
+            resolve_source("var a = 1; @pure func f(x) { return x + a; }")
+                .unwrap_err()
+                .to_string(),
+            "Line 1: Pure function 'f' cannot use 'a' from outside of it"
+        );
+        // Natives are globals too
+        assert!(resolve_source("@pure func f() { return clock(); }").is_err());
+        // Only the pure function is checked
+        assert!(resolve_source("@pure func f() { return 1; } func g() { print 1; }").is_ok());
+    }
+
+    #[test]
+    fn this_outside_class_errors() {
+        assert_eq!(
+            resolve_source("func f() { return this; }")
+                .unwrap_err()
+                .to_string(),
+            "Line 1: Cannot use 'this' outside of a class"
+        );
+        assert!(resolve_source("class A { f() { return func () { return this.x; }; } }").is_ok());
+    }
+
+    #[test]
+    fn return_outside_function_errors() {
+        assert_eq!(
+            resolve_source("return 1;\nprint \"after\";")
+                .unwrap_err()
+                .to_string(),
+            "Line 1: Cannot return from top-level code"
+        );
+        assert!(resolve_source("{ var x = { return 1; 2 }; }").is_err());
+        assert!(resolve_source("func f() { var g = func () { return 1; }; return g(); }").is_ok());
+        assert!(resolve_source("class A { f() { return 1; } }").is_ok());
+    }
+
+    #[test]
+    fn property_objects_are_resolved() {
+        let source = "class A {} { var a = A(); { a.x = a.y; } }";
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        let stmts = Parser::new(tokens).parse().unwrap();
+        let interpreter = Rc::new(RefCell::new(Interpreter::new()));
+        let mut resolver = Resolver::new(interpreter.clone());
+        resolver.resolve_many(&stmts.iter().collect()).unwrap();
+
+        let Stmt::Block { stmts: outer, span: _ } = &stmts[1] else {
+            panic!("Expected a block");
+        };
+        let Stmt::Block { stmts: inner, span: _ } = outer[1].as_ref() else {
+            panic!("Expected a block");
+        };
+        let Stmt::Expression {
+            expression:
+                Expr::Set {
+                    object,
+                    name: _,
+                    value,
+                    span: _,
+                },
+            span: _,
+        } = inner[0].as_ref()
+        else {
+            panic!("Expected a set");
+        };
+        let distance = |expr: &Expr| match expr {
+            Expr::Variable { id, name: _, span: _ } => interpreter.borrow().get_distance(*id),
+            Expr::Get {
+                object,
+                name: _,
+                span: _,
+            } => match object.as_ref() {
+                Expr::Variable { id, name: _, span: _ } => interpreter.borrow().get_distance(*id),
+                _ => panic!("Expected a variable"),
+            },
+            _ => panic!("Expected a variable or get"),
+        };
+        assert_eq!(distance(object), Some(1));
+        assert_eq!(distance(value), Some(1));
+    }
+
+    #[test]
+    fn own_initializer_errors() {
+        assert!(resolve_source("{ var a = a; }").is_err());
     }
 }
@@ -1,3 +1,4 @@
+use crate::expr::Span;
 use crate::TokenType::*;
 use core::fmt;
 use std::{collections::HashMap, error::Error, string::String};
@@ -10,6 +11,15 @@ pub struct Scanner {
     current: usize,
     line: usize,
     keywords: HashMap<&'static str, TokenType>,
+    // When set a newline outside of parens ends the statement like a ';'
+    newline_terminators: bool,
+    paren_depth: usize,
+    // Scanning stops with an error once more tokens than this are produced
+    max_tokens: Option<usize>,
+    // Lines longer than this many chars get a warning
+    max_line_length: Option<usize>,
+    // Suspicious source that still scans fine and where it is
+    pub warnings: Vec<(Span, String)>,
 }
 
 //Helper functions
@@ -25,6 +35,25 @@ fn is_alpha_num(ch: char) -> bool {
     is_alpha(ch) || is_digit(ch)
 }
 
+// Turn the \n, \t and \\ of a '"' string into the real chars, raw strings skip this
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n')) => out.push('\n'),
+            ('\\', Some('t')) => out.push('\t'),
+            ('\\', Some('\\')) => out.push('\\'),
+            _ => {
+                out.push(c);
+                continue;
+            }
+        }
+        chars.next();
+    }
+    out
+}
+
 impl Scanner {
     pub fn new(source: &str) -> Self {
         Self {
@@ -50,15 +79,51 @@ impl Scanner {
                 ("while", While),
                 ("super", Super),
                 ("var", Var),
+                ("scope", Scope),
+                ("lazy", Lazy),
             ]),
+            newline_terminators: false,
+            paren_depth: 0,
+            max_tokens: None,
+            max_line_length: None,
+            warnings: vec![],
         }
     }
 
+    // Limit how many tokens can be scanned, for running untrusted input
+    #[allow(dead_code)]
+    pub fn with_max_tokens(mut self, max_tokens: usize) -> Self {
+        self.max_tokens = Some(max_tokens);
+        self
+    }
+
+    // Warn about lines longer than max_line_length chars, the warning shows only that much of it
+    pub fn with_max_line_length(mut self, max_line_length: usize) -> Self {
+        self.max_line_length = Some(max_line_length);
+        self
+    }
+
+    // Opt-in mode where newlines act as statement terminators
+    #[allow(dead_code)]
+    pub fn with_newline_terminators(mut self) -> Self {
+        self.newline_terminators = true;
+        self
+    }
+
     // Main scanner function that is invoked from the main
     // Returns a list of tokens in the whole buffer given
     // Stores a list of errors and returns them together in a long list
     pub fn scan_tokens(&mut self) -> Result<Vec<Token>, Box<dyn Error>> {
         let mut errors = vec![];
+        // Roughly one token per four bytes of source, saves reallocating on big inputs
+        let mut capacity = self.source.len() / 4;
+        if let Some(max) = self.max_tokens {
+            capacity = capacity.min(max + 1);
+        }
+        self.tokens.reserve(capacity);
+        if let Some(max) = self.max_line_length {
+            self.check_line_lengths(max);
+        }
         // While not at the end of the file keep on going
         while !self.is_at_end() {
             // shift the start index to where the previous run ended
@@ -68,6 +133,20 @@ impl Scanner {
             if let Err(e) = self.scan_token() {
                 errors.push(e)
             }
+            if let Some(max) = self.max_tokens {
+                if self.tokens.len() > max {
+                    return Err(format!(
+                        "Line {}: Input has more than {} tokens",
+                        self.line, max
+                    )
+                    .into());
+                }
+            }
+        }
+        // The last line does not need a trailing newline to be terminated
+        if self.newline_terminators && self.paren_depth == 0 && self.ends_statement() {
+            self.start = self.current;
+            self.add_token(Semicolon);
         }
         // After scanning everything push a EOF Token at the end
         self.tokens.push(Token {
@@ -75,6 +154,8 @@ impl Scanner {
             lexeme: "".to_string(),
             literal: None,
             line_number: self.line,
+            offset: self.source.len(),
+            length: 0,
         });
 
         // If any error print all of them together
@@ -99,8 +180,14 @@ impl Scanner {
         let c = self.advance();
 
         match c {
-            '(' => self.add_token(LeftParen),
-            ')' => self.add_token(RightParen),
+            '(' => {
+                self.paren_depth += 1;
+                self.add_token(LeftParen);
+            }
+            ')' => {
+                self.paren_depth = self.paren_depth.saturating_sub(1);
+                self.add_token(RightParen);
+            }
             '{' => self.add_token(LeftBrace),
             '}' => self.add_token(RightBrace),
             ',' => self.add_token(Comma),
@@ -108,7 +195,14 @@ impl Scanner {
             '+' => self.add_token(Plus),
             '-' => self.add_token(Minus),
             ';' => self.add_token(Semicolon),
-            '*' => self.add_token(Star),
+            ':' => self.add_token(Colon),
+            '?' => self.add_token(Question),
+            '*' => {
+                let token = if self.char_match('*') { StarStar } else { Star };
+                self.add_token(token);
+            }
+            '%' => self.add_token(Percent),
+            '@' => self.add_token(At),
 
             '!' => {
                 let token = if self.char_match('=') {
@@ -118,6 +212,11 @@ impl Scanner {
                 };
                 self.add_token(token);
             }
+            // Only '~=' exists, a lone '~' falls through to the unrecognised char error
+            '~' if self.peek() == '=' => {
+                self.advance();
+                self.add_token(TildeEqual);
+            }
             '=' => {
                 let token = if self.char_match('=') {
                     EqualEqual
@@ -156,12 +255,20 @@ impl Scanner {
                 };
             }
 
+            '"' if self.peek() == '"' && self.peek_next() == '"' => {
+                self.raw_string_literal()?;
+            }
             '"' => {
                 self.string_literal()?;
             }
 
             ' ' | '\r' | '\t' => (),
-            '\n' => self.line += 1,
+            '\n' => {
+                if self.newline_terminators && self.paren_depth == 0 && self.ends_statement() {
+                    self.add_token(Semicolon);
+                }
+                self.line += 1;
+            }
 
             c => {
                 if is_digit(c) {
@@ -224,6 +331,17 @@ impl Scanner {
         Ok(())
     }
 
+    // Check if the last token can end a statement so a newline may terminate it
+    fn ends_statement(&self) -> bool {
+        match self.tokens.last() {
+            Some(token) => matches!(
+                token.token_type,
+                Identifier | String_ | Number | True | False | Nil | This | RightParen | Return
+            ),
+            None => false,
+        }
+    }
+
     // Helper function to check if current char matches a given char and moves current by one
     fn char_match(&mut self, c: char) -> bool {
         if self.is_at_end() {
@@ -239,6 +357,7 @@ impl Scanner {
 
     // Called when we encounter '"'
     fn string_literal(&mut self) -> Result<(), Box<dyn Error>> {
+        let start_line = self.line;
         // Keep on going till the source ends or u find the closeing '"'
         while !self.is_at_end() && self.peek() != '"' {
             if self.peek() == '\n' {
@@ -253,8 +372,48 @@ impl Scanner {
         }
         // Get the literal as a string and then convert it into a LiteralValue enum
         let literal = &self.source.as_str()[self.start + 1..self.current - 1];
+        let literal = LiteralValue::StringValue(unescape(literal));
+        self.add_token_at_line(String_, Some(literal), start_line);
+        Ok(())
+    }
+
+    // Push a warning for every line longer than max chars
+    fn check_line_lengths(&mut self, max: usize) {
+        let mut offset = 0;
+        for line in self.source.split('\n') {
+            let length = line.chars().count();
+            if length > max {
+                let snippet: String = line.chars().take(max).collect();
+                self.warnings.push((
+                    (offset, offset + line.len()),
+                    format!(
+                        "Line is {} chars long, more than {}: {}...",
+                        length, max, snippet
+                    ),
+                ));
+            }
+            offset += line.len() + 1;
+        }
+    }
+
+    // A """ delimited string, may have single quotes and newlines in it
+    // Nothing is processed inside so it can hold SQL or HTML as it is written
+    fn raw_string_literal(&mut self) -> Result<(), Box<dyn Error>> {
+        let start_line = self.line;
+        // Skip the rest of the opening quotes
+        self.current += 2;
+        while !self.source[self.current..].starts_with("\"\"\"") {
+            if self.is_at_end() {
+                return Err(format!("Line {}: Raw string is not terminated", start_line).into());
+            }
+            if self.advance() == '\n' {
+                self.line += 1;
+            }
+        }
+        self.current += 3;
+        let literal = &self.source.as_str()[self.start + 3..self.current - 3];
         let literal = LiteralValue::StringValue(literal.to_string());
-        self.add_token_lit(String_, Some(literal));
+        self.add_token_at_line(String_, Some(literal), start_line);
         Ok(())
     }
 
@@ -282,12 +441,24 @@ impl Scanner {
 
     // Add a token to the struct tokens vector
     fn add_token_lit(&mut self, token_type: TokenType, literal: Option<LiteralValue>) {
+        self.add_token_at_line(token_type, literal, self.line);
+    }
+
+    // Tokens spanning several lines, like multi-line strings, are on the line they start on
+    fn add_token_at_line(
+        &mut self,
+        token_type: TokenType,
+        literal: Option<LiteralValue>,
+        line_number: usize,
+    ) {
         let text = &self.source.as_str()[self.start..self.current];
         self.tokens.push(Token {
             token_type,
             lexeme: text.to_string(),
             literal,
-            line_number: self.line,
+            line_number,
+            offset: self.start,
+            length: self.current - self.start,
         })
     }
 
@@ -314,11 +485,17 @@ pub enum TokenType {
     Plus,
     Minus,
     Semicolon,
+    Colon,
+    Question,
     Slash,
     Star,
+    StarStar,
+    Percent,
+    At,
 
     Bang,
     BangEqual,
+    TildeEqual,
     Greater,
     GreaterEqual,
     Less,
@@ -346,6 +523,8 @@ pub enum TokenType {
     Super,
     This,
     Var,
+    Scope,
+    Lazy,
 
     Eof,
 }
@@ -370,12 +549,24 @@ pub struct Token {
     pub lexeme: String,
     pub literal: Option<LiteralValue>,
     pub line_number: usize,
+    // Byte offset of the first char of the token in the source and its byte length
+    pub offset: usize,
+    pub length: usize,
 }
 
 impl Token {
+    // Type and lexeme, the literal is only shown if the token has one
     #[allow(clippy::inherent_to_string, dead_code)]
     pub fn to_string(&self) -> String {
-        format!("{} {} {:?}", self.token_type, self.lexeme, self.literal)
+        match &self.literal {
+            Some(LiteralValue::FloatValue(x)) => {
+                format!("{} '{}' = {}", self.token_type, self.lexeme, x)
+            }
+            Some(LiteralValue::StringValue(s)) => {
+                format!("{} '{}' = {:?}", self.token_type, self.lexeme, s)
+            }
+            None => format!("{} '{}'", self.token_type, self.lexeme),
+        }
     }
 }
 
@@ -383,9 +574,32 @@ impl Token {
 mod tests {
     use super::*;
 
+    #[test]
+    fn handle_star_star() -> Result<(), Box<dyn Error>> {
+        let mut scanner = Scanner::new("2 ** 3 * 4 ***");
+        scanner.scan_tokens()?;
+
+        let types: Vec<TokenType> = scanner.tokens.iter().map(|t| t.token_type.clone()).collect();
+        assert_eq!(
+            types,
+            vec![Number, StarStar, Number, Star, Number, StarStar, Star, Eof]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn handle_tilde_equal() -> Result<(), Box<dyn Error>> {
+        let mut scanner = Scanner::new("a ~= b");
+        scanner.scan_tokens()?;
+        assert_eq!(scanner.tokens[1].token_type, TildeEqual);
+        assert_eq!(scanner.tokens[1].lexeme, "~=");
+        assert!(Scanner::new("a ~ b").scan_tokens().is_err());
+        Ok(())
+    }
+
     #[test]
     fn handle_single_char_tokens() -> Result<(), Box<dyn Error>> {
-        let source = "(){}=/-+*.,;";
+        let source = "(){}=/-+*.,;%";
         let mut scanner = Scanner::new(source);
         scanner.scan_tokens()?;
 
@@ -402,7 +616,8 @@ mod tests {
         assert_eq!(scanner.tokens[9].token_type, Dot);
         assert_eq!(scanner.tokens[10].token_type, Comma);
         assert_eq!(scanner.tokens[11].token_type, Semicolon);
-        assert_eq!(scanner.tokens[12].token_type, Eof);
+        assert_eq!(scanner.tokens[12].token_type, Percent);
+        assert_eq!(scanner.tokens[13].token_type, Eof);
 
         Ok(())
     }
@@ -425,29 +640,29 @@ mod tests {
 
     #[test]
     fn check_is_digit() -> Result<(), Box<dyn Error>> {
-        assert_eq!(is_digit('0'), true);
-        assert_eq!(is_digit('1'), true);
-        assert_eq!(is_digit('2'), true);
-        assert_eq!(is_digit('3'), true);
-        assert_eq!(is_digit('4'), true);
-        assert_eq!(is_digit('5'), true);
-        assert_eq!(is_digit('6'), true);
-        assert_eq!(is_digit('7'), true);
-        assert_eq!(is_digit('8'), true);
-        assert_eq!(is_digit('9'), true);
-        assert_eq!(is_digit('i'), false);
+        assert!(is_digit('0'));
+        assert!(is_digit('1'));
+        assert!(is_digit('2'));
+        assert!(is_digit('3'));
+        assert!(is_digit('4'));
+        assert!(is_digit('5'));
+        assert!(is_digit('6'));
+        assert!(is_digit('7'));
+        assert!(is_digit('8'));
+        assert!(is_digit('9'));
+        assert!(!is_digit('i'));
         Ok(())
     }
 
     #[test]
     fn check_is_alpha() -> Result<(), Box<dyn Error>> {
-        assert_eq!(is_alpha('a'), true);
-        assert_eq!(is_alpha('z'), true);
-        assert_eq!(is_alpha('A'), true);
-        assert_eq!(is_alpha('Z'), true);
-        assert_eq!(is_alpha('-'), false);
-        assert_eq!(is_alpha('f'), true);
-        assert_eq!(is_alpha('F'), true);
+        assert!(is_alpha('a'));
+        assert!(is_alpha('z'));
+        assert!(is_alpha('A'));
+        assert!(is_alpha('Z'));
+        assert!(!is_alpha('-'));
+        assert!(is_alpha('f'));
+        assert!(is_alpha('F'));
         Ok(())
     }
 
@@ -477,6 +692,48 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn multi_line_string_starts_its_line() -> Result<(), Box<dyn Error>> {
+        let source = "print 1;\nprint \"two\nlines\";\nprint 3;";
+        let mut scanner = Scanner::new(source);
+        scanner.scan_tokens()?;
+
+        assert_eq!(scanner.tokens[4].token_type, String_);
+        assert_eq!(scanner.tokens[4].line_number, 2);
+        assert_eq!(scanner.tokens[5].line_number, 3);
+        assert_eq!(scanner.tokens[7].line_number, 4);
+        Ok(())
+    }
+
+    #[test]
+    fn unescape_common_sequences() {
+        assert_eq!(unescape("a\\nb\\tc"), "a\nb\tc");
+        assert_eq!(unescape("a\\\\nb"), "a\\nb");
+        // Unknown sequences and a trailing backslash are kept as they are
+        assert_eq!(unescape("\\q\\"), "\\q\\");
+    }
+
+    #[test]
+    fn raw_string_test() -> Result<(), Box<dyn Error>> {
+        let source = "var q = \"\"\"SELECT \"name\"\nFROM t\"\"\";\nprint q;";
+        let mut scanner = Scanner::new(source);
+        scanner.scan_tokens()?;
+
+        let token = &scanner.tokens[3];
+        assert_eq!(token.token_type, String_);
+        match &token.literal {
+            Some(LiteralValue::StringValue(s)) => assert_eq!(s, "SELECT \"name\"\nFROM t"),
+            _ => panic!("Expected a string literal"),
+        }
+        // The string is on the line it starts on but the newline inside counts for what follows
+        assert_eq!(token.line_number, 1);
+        assert_eq!(scanner.tokens[5].line_number, 3);
+
+        let err = Scanner::new("\n\"\"\"open\n\"\"").scan_tokens().unwrap_err();
+        assert_eq!(err.to_string(), "Line 2: Raw string is not terminated\n");
+        Ok(())
+    }
+
     #[test]
     fn number_literal_test() -> Result<(), Box<dyn Error>> {
         let source = "123.321\n432432.43242\n5.\n1\n.1";
@@ -529,6 +786,120 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn newline_terminators_test() -> Result<(), Box<dyn Error>> {
+        let with_semicolons = "var x = (1 +\n 2);\nprint x;\nfunc f(a) {\n  return a;\n}\nf(x);\n";
+        let without_semicolons = "var x = (1 +\n 2)\nprint x\nfunc f(a) {\n  return a\n}\nf(x)";
+
+        let expected = Scanner::new(with_semicolons).scan_tokens()?;
+        let tokens = Scanner::new(without_semicolons)
+            .with_newline_terminators()
+            .scan_tokens()?;
+
+        assert_eq!(tokens.len(), expected.len());
+        for (token, expected) in tokens.iter().zip(expected.iter()) {
+            assert_eq!(token.token_type, expected.token_type);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn newline_terminators_off_by_default() -> Result<(), Box<dyn Error>> {
+        let source = "var x = 1\nprint x\n";
+        let mut scanner = Scanner::new(source);
+        scanner.scan_tokens()?;
+
+        assert!(scanner.tokens.iter().all(|t| t.token_type != Semicolon));
+
+        Ok(())
+    }
+
+    #[test]
+    fn max_tokens_test() {
+        let source = "1 + ".repeat(100) + "1;";
+        let result = Scanner::new(&source).with_max_tokens(50).scan_tokens();
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Line 1: Input has more than 50 tokens"
+        );
+
+        let result = Scanner::new(&source).with_max_tokens(500).scan_tokens();
+        assert!(result.is_ok());
+    }
+
+    // See the timing with `cargo test large_source_test -- --nocapture`
+    #[test]
+    fn large_source_test() -> Result<(), Box<dyn Error>> {
+        let line = "var a = (b + 12.5) * \"str\"; // comment\n";
+        let source = line.repeat(20_000);
+        let start = std::time::Instant::now();
+        let tokens = Scanner::new(&source).scan_tokens()?;
+        println!("Scanned {} bytes in {:?}", source.len(), start.elapsed());
+
+        let small = Scanner::new(line).scan_tokens()?;
+        // Every line gives the same tokens apart from the EOF
+        assert_eq!(tokens.len(), (small.len() - 1) * 20_000 + 1);
+        for (i, token) in tokens[..tokens.len() - 1].iter().enumerate() {
+            let expected = &small[i % (small.len() - 1)];
+            assert_eq!(token.token_type, expected.token_type);
+            assert_eq!(token.lexeme, expected.lexeme);
+            assert_eq!(token.line_number, i / (small.len() - 1) + 1);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn max_line_length_test() -> Result<(), Box<dyn Error>> {
+        let source = "var a = 1;\nvar long_name = 1 + 2 + 3;\nprint a;";
+        let mut scanner = Scanner::new(source).with_max_line_length(12);
+        scanner.scan_tokens()?;
+        assert_eq!(
+            scanner.warnings,
+            vec![(
+                (11, 37),
+                "Line is 26 chars long, more than 12: var long_nam...".to_string()
+            )]
+        );
+
+        // Off by default
+        let mut scanner = Scanner::new(source);
+        scanner.scan_tokens()?;
+        assert!(scanner.warnings.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn token_to_string_test() -> Result<(), Box<dyn Error>> {
+        let tokens = Scanner::new("while x 3.14 \"hi\";").scan_tokens()?;
+
+        assert_eq!(tokens[0].to_string(), "While 'while'");
+        assert_eq!(tokens[1].to_string(), "Identifier 'x'");
+        assert_eq!(tokens[2].to_string(), "Number '3.14' = 3.14");
+        assert_eq!(tokens[3].to_string(), "String_ '\"hi\"' = \"hi\"");
+        Ok(())
+    }
+
+    #[test]
+    fn token_offsets_test() -> Result<(), Box<dyn Error>> {
+        let source = "var name = \"héllo\";\nprint name;";
+        let tokens = Scanner::new(source).scan_tokens()?;
+
+        assert_eq!(tokens[1].lexeme, "name");
+        assert_eq!((tokens[1].offset, tokens[1].length), (4, 4));
+        // Offsets and lengths are in bytes, the string has a two byte char
+        assert_eq!(tokens[3].token_type, String_);
+        assert_eq!((tokens[3].offset, tokens[3].length), (11, 8));
+        assert_eq!(tokens[6].lexeme, "name");
+        assert_eq!((tokens[6].offset, tokens[6].length), (27, 4));
+        let eof = tokens.last().unwrap();
+        assert_eq!((eof.offset, eof.length), (source.len(), 0));
+        for token in &tokens {
+            assert_eq!(&source[token.offset..token.offset + token.length], token.lexeme);
+        }
+        Ok(())
+    }
+
     #[test]
     fn full_test() -> Result<(), Box<dyn Error>> {
         let source = "var x = 10;\nwhile x>1 { print(\"hello\"); }";
@@ -1,4 +1,4 @@
-use crate::expr::Expr;
+use crate::expr::{Expr, Span};
 use crate::scanner::Token;
 
 #[derive(Debug,Clone)]
@@ -6,35 +6,58 @@ use crate::scanner::Token;
 pub enum Stmt {
     Expression {
         expression: Expr,
+        span: Span,
     },
     Print {
         expression: Expr,
+        span: Span,
     },
     Var {
         name: Token,
         initializer: Expr,
+        // Optional type from 'var x: Number', checked whenever the variable is set
+        type_name: Option<Token>,
+        // 'lazy var', the initializer runs on the first read instead of here
+        lazy: bool,
+        span: Span,
     },
     Block {
         stmts: Vec<Box<Stmt>>,
+        span: Span,
     },
     IfElse {
         predicate: Expr,
         then_branch: Box<Stmt>,
         else_branch: Option<Box<Stmt>>,
+        span: Span,
     },
     WhileLoop {
         cond: Expr,
         body: Box<Stmt>,
+        else_branch: Option<Box<Stmt>>,
+        span: Span,
     },
     Function {
         name: Token,
         params: Vec<Token>,
-        body: Vec<Box<Stmt>>
+        body: Vec<Box<Stmt>>,
+        // Marked with @pure, may not print or touch anything outside itself
+        pure: bool,
+        span: Span,
+    },
+    // Every method is a Stmt::Function, fields are 'name = default;' lines set on each new
+    // instance before init runs
+    Class {
+        name: Token,
+        fields: Vec<(Token, Expr)>,
+        methods: Vec<Stmt>,
+        span: Span,
     },
     #[allow(dead_code)]
     Return {
         keyword: Token,
         value: Option<Expr>,
+        span: Span,
     },
 }
 
@@ -45,27 +68,162 @@ impl Stmt {
             Stmt::Var {
                 name,
                 initializer: _,
+                type_name: _,
+                lazy: true,
+                span: _,
+            } => format!("(lazy var {})", name.lexeme),
+            Stmt::Var {
+                name,
+                initializer: _,
+                type_name: _,
+                lazy: false,
+                span: _,
             } => format!("(var {})", name.lexeme),
-            Stmt::Print { expression } => format!("(print {})", expression.to_string()),
-            Stmt::Expression { expression } => expression.to_string(),
-            Stmt::Block { stmts } => stmts
+            Stmt::Print {
+                expression,
+                span: _,
+            } => format!("(print {})", expression.to_string()),
+            Stmt::Expression {
+                expression,
+                span: _,
+            } => expression.to_string(),
+            Stmt::Block { stmts, span: _ } => stmts
                 .iter()
                 .map(|stmt| stmt.to_string())
                 .collect::<String>(),
             Stmt::IfElse {
-                predicate: _,
+                predicate,
+                then_branch,
+                else_branch,
+                span: _,
+            } => match else_branch {
+                Some(els) => format!(
+                    "(if {} {} else {})",
+                    predicate.to_string(),
+                    then_branch.to_string(),
+                    els.to_string()
+                ),
+                None => format!("(if {} {})", predicate.to_string(), then_branch.to_string()),
+            },
+            Stmt::WhileLoop {
+                cond,
+                body,
+                else_branch,
+                span: _,
+            } => match else_branch {
+                Some(els) => format!(
+                    "(while {} {} else {})",
+                    cond.to_string(),
+                    body.to_string(),
+                    els.to_string()
+                ),
+                None => format!("(while {} {})", cond.to_string(), body.to_string()),
+            },
+            Stmt::Function {
+                name,
+                params,
+                body: _,
+                pure: _,
+                span: _,
+            } => format!(
+                "(func {} ({}))",
+                name.lexeme,
+                params
+                    .iter()
+                    .map(|p| p.lexeme.clone())
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ),
+            Stmt::Class {
+                name,
+                fields,
+                methods,
+                span: _,
+            } => format!(
+                "(class {} {})",
+                name.lexeme,
+                fields
+                    .iter()
+                    .map(|(name, value)| format!("(field {} {})", name.lexeme, value.to_string()))
+                    .chain(methods.iter().map(|m| m.to_string()))
+                    .collect::<Vec<String>>()
+                    .join(" ")
+            ),
+            Stmt::Return {
+                keyword: _,
+                value,
+                span: _,
+            } => match value {
+                Some(expr) => format!("(return {})", expr.to_string()),
+                None => "(return)".to_string(),
+            },
+        }
+    }
+
+    // Best effort line number of the statement used for tracing
+    pub fn line(&self) -> Option<usize> {
+        match self {
+            Stmt::Expression {
+                expression,
+                span: _,
+            } => expression.line(),
+            Stmt::Print {
+                expression,
+                span: _,
+            } => expression.line(),
+            Stmt::Var {
+                name,
+                initializer: _,
+                type_name: _,
+                lazy: _,
+                span: _,
+            } => Some(name.line_number),
+            Stmt::Block { stmts, span: _ } => stmts.first().and_then(|s| s.line()),
+            Stmt::IfElse {
+                predicate,
                 then_branch: _,
                 else_branch: _,
-            } => {
-                todo!()
-            }
-            Stmt::WhileLoop { cond: _, body: _ } => {
-                todo!()
-            }
-            Stmt::Function { name:_, params:_, body:_ } => {
-                todo!()
-            }
-            Stmt::Return {keyword:_, value:_ } => todo!()
+                span: _,
+            } => predicate.line(),
+            Stmt::WhileLoop {
+                cond,
+                body,
+                else_branch: _,
+                span: _,
+            } => cond.line().or_else(|| body.line()),
+            Stmt::Function {
+                name,
+                params: _,
+                body: _,
+                pure: _,
+                span: _,
+            } => Some(name.line_number),
+            Stmt::Class {
+                name,
+                fields: _,
+                methods: _,
+                span: _,
+            } => Some(name.line_number),
+            Stmt::Return {
+                keyword,
+                value: _,
+                span: _,
+            } => Some(keyword.line_number),
+        }
+    }
+
+    // Where in the source the statement was parsed from
+    pub fn span(&self) -> Span {
+        match self {
+            Stmt::Expression { span, .. }
+            | Stmt::Print { span, .. }
+            | Stmt::Var { span, .. }
+            | Stmt::Block { span, .. }
+            | Stmt::IfElse { span, .. }
+            | Stmt::WhileLoop { span, .. }
+            | Stmt::Function { span, .. }
+            | Stmt::Class { span, .. }
+            | Stmt::Return { span, .. } => *span,
         }
     }
 }
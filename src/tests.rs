@@ -1,7 +1,9 @@
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod tests {
     use std::fs::{read_dir, read_to_string, DirEntry};
-    use std::process::Command;
+    use std::io::Write;
+    use std::process::{Command, Output, Stdio};
 
     #[test]
     fn execute_tests() {
@@ -24,11 +26,286 @@ mod tests {
             }
         }
 
-        if errors.len() > 0 {
+        if !errors.is_empty() {
             panic!("Errors:\n\n{}", errors.join("\n\n"));
         }
     }
 
+    // Command running the interpreter with the flags, and the code if there is some
+    // Without code the file given in flags is run, or the REPL if there is none
+    fn cli(flags: &[&str], code: Option<&str>) -> Command {
+        let mut command = Command::new("cargo");
+        command.arg("run").arg("-q").arg("--").args(flags);
+        if let Some(code) = code {
+            command.arg("e").arg(code);
+        }
+        command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        command
+    }
+
+    // Run the command from cli with stdin written to it
+    fn run_cli(flags: &[&str], code: Option<&str>, stdin: &[u8]) -> Output {
+        run_with_stdin(cli(flags, code), stdin)
+    }
+
+    // For commands from cli that need more setup, like an env var
+    fn run_with_stdin(mut command: Command, stdin: &[u8]) -> Output {
+        let mut child = command.spawn().unwrap();
+        child.stdin.take().unwrap().write_all(stdin).unwrap();
+        child.wait_with_output().unwrap()
+    }
+
+    fn stdout(output: Output) -> String {
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    fn run_repl(args: &[&str], input: &[u8]) -> String {
+        stdout(run_cli(args, None, input))
+    }
+
+    #[test]
+    fn eprint_writes_to_stderr() {
+        let output = run_cli(&[], Some("print 1; eprint(2); print 3;"), b"");
+        let stderr = String::from_utf8(output.stderr.clone()).unwrap();
+
+        assert_eq!(stdout(output), "1\n3\n");
+        assert!(stderr.ends_with("2\n"));
+    }
+
+    // eprint shows a string exactly as print does, escapes and all
+    #[test]
+    fn eprint_matches_print() {
+        let code = r#"var s = "a\tb\\n" + """c\n"""; print s; eprint(s);"#;
+        let output = run_cli(&[], Some(code), b"");
+        let stderr = String::from_utf8(output.stderr.clone()).unwrap();
+
+        assert_eq!(stdout(output), "a\tb\\nc\\n\n");
+        assert!(stderr.ends_with("a\tb\\nc\\n\n"));
+    }
+
+    // stdout is buffered, so it has to be flushed before anything is written to stderr
+    #[test]
+    fn stdout_and_stderr_stay_in_order() {
+        let path = std::env::temp_dir().join(format!("tox_order_{}", std::process::id()));
+        let file = std::fs::File::create(&path).unwrap();
+        let mut command = cli(&["--trace"], Some("print 1;\neprint(2);\nprint 3;"));
+        command.stdout(file.try_clone().unwrap()).stderr(file);
+        run_with_stdin(command, b"");
+        let merged = read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            merged,
+            "[line ?] (print 1)\n1\n[line 2] <fn (var eprint)> [2]\n2\n[line ?] (print 3)\n3\n"
+        );
+    }
+
+    #[test]
+    fn trace_prints_statements() {
+        let output = run_cli(&["--trace"], Some("var a = 1;\nprint a + 2;"), b"");
+        let stderr = String::from_utf8(output.stderr.clone()).unwrap();
+
+        assert_eq!(stdout(output), "3\n");
+        assert!(stderr.contains("[line 1] (var a)"));
+        assert!(stderr.contains("[line 2] (print (+ (var a) 2))"));
+    }
+
+    #[test]
+    fn check_warns_on_infinite_while() {
+        let check = |code: &str| stdout(run_cli(&["--check"], Some(code), b""));
+
+        // Nothing is run, only the warning is printed
+        assert_eq!(
+            check("var a = 1;\nwhile (true) {\n  print a;\n}"),
+            "Warning: Line 2: while (true) loop never ends\n"
+        );
+        assert_eq!(check("func f() {\n  while (true) {\n    return 1;\n  }\n}"), "");
+    }
+
+    #[test]
+    fn lenient_nil_flag() {
+        let run = |flags: &[&str]| {
+            stdout(run_cli(flags, Some("var a; print a + 1; print \"s\" + a;"), b""))
+        };
+
+        assert_eq!(run(&[]), "Error: Plus Not implemented on 'Nil' and 'Number'\n");
+        assert_eq!(run(&["--lenient-nil"]), "1\ns\n");
+    }
+
+    #[test]
+    fn print_function_flag() {
+        let run = |flags: &[&str], code: &str| stdout(run_cli(flags, Some(code), b""));
+
+        let flag = ["--print-function"];
+        assert_eq!(run(&flag, "print(\"hi\"); print \"hi\";"), "hi\nhi\n");
+        assert_eq!(run(&flag, "var p = print; p(1 + 2); print(4) == nil;"), "3\n4\n");
+        assert_eq!(run(&[], "print \"hi\"; print(1) + 1;"), "hi\n2\n");
+    }
+
+    #[test]
+    fn check_warns_on_long_lines() {
+        let flags = ["--check", "--max-line-length=10"];
+        let output = run_cli(&flags, Some("var a = 1;\nprint a + a + a;"), b"");
+        assert_eq!(
+            stdout(output),
+            "Warning: Line 2: Line is 16 chars long, more than 10: print a + ...\n"
+        );
+    }
+
+    #[test]
+    fn file_may_end_without_semicolon() {
+        let path = std::env::temp_dir().join(format!("tox_eof_{}.tox", std::process::id()));
+        std::fs::write(&path, "var a = 1;\nprint a;\nprint a + 1\n").unwrap();
+        let output = run_cli(&[path.to_str().unwrap()], None, b"");
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(stdout(output), "1\n2\n");
+    }
+
+    #[test]
+    fn input_reads_piped_lines() {
+        let path = std::env::temp_dir().join(format!("tox_input_{}.tox", std::process::id()));
+        std::fs::write(
+            &path,
+            "var name = input(\"Name: \");\nprint \"Hi \" + name;\nvar line = input();\nwhile (line != nil) {\n  print line;\n  line = input();\n}\nprint line == nil;\n",
+        )
+        .unwrap();
+        let output = run_cli(&[path.to_str().unwrap()], None, b"Ada\nfirst\nsecond\n");
+        std::fs::remove_file(&path).unwrap();
+
+        // Read lines are compared with nil until input() hits the end of input
+        assert_eq!(stdout(output), "Name: Hi Ada\nfirst\nsecond\ntrue\n");
+    }
+
+    #[test]
+    fn time_it_prints_duration_and_returns_result() {
+        let code = "func work() { var s = 0; for (var i = 0; i < 1000; i = i + 1) s = s + i; return s; }
+                print time_it(work);";
+        let stdout = stdout(run_cli(&[], Some(code), b""));
+        let lines: Vec<&str> = stdout.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let ms = lines[0]
+            .strip_prefix("work took ")
+            .and_then(|rest| rest.strip_suffix("ms"))
+            .unwrap();
+        assert!(ms.parse::<f64>().unwrap() >= 0.0);
+        assert_eq!(lines[1], "499500");
+    }
+
+    #[test]
+    fn breakpoint_inspects_variables() {
+        let code = "func f() { var a = 41; breakpoint(); print a + 1; } f();";
+        let output = run_cli(&[], Some(code), b"print a;\na = 1;\n.continue\n");
+
+        // The assignment at the breakpoint is seen by the function
+        assert_eq!(
+            stdout(output),
+            "(breakpoint) > 41\n(breakpoint) > (breakpoint) > 2\n"
+        );
+
+        // A function of the same name is just called
+        let code = "func breakpoint() { print \"mine\"; } breakpoint();";
+        assert_eq!(stdout(run_cli(&[], Some(code), b"")), "mine\n");
+    }
+
+    #[test]
+    fn prompt_and_echo_are_configurable() {
+        let path = std::env::temp_dir().join(format!("tox_prompt_{}", std::process::id()));
+        let flag = format!("--repl-history={}", path.display());
+        let mut command = cli(&[&flag], None);
+        command.env("TOX_PROMPT", "tox$ ");
+        let output = run_with_stdin(
+            command,
+            b"1 + 2;\n.prompt >> \n.echo = \nprint 5;\n3 * 2;\n.echo off\n4;\n.echo on\n5;\n",
+        );
+
+        // Nothing is echoed for 4 while the echo is off
+        assert_eq!(
+            stdout(output),
+            "tox$ => 3\n\ntox$ >> >> 5\n\n>> = 6\n\n>> >> \n>> >> => 5\n\n>> "
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn repl_history_persists() {
+        let path = std::env::temp_dir().join(format!("tox_history_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let flag = format!("--repl-history={}", path.display());
+
+        run_repl(&[&flag], b"var a = 1;\nprint a;\n");
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "var a = 1;\nprint a;\n"
+        );
+
+        let stdout = run_repl(&[&flag], b".history\n");
+        assert_eq!(stdout, "> 1: var a = 1;\n2: print a;\n> ");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn repl_inspect_shows_types() {
+        let stdout = run_repl(&[], b".inspect 1;\n.inspect \"1\";\n");
+        assert_eq!(stdout, "> 1 : Number\n\n> \"1\" : String\n\n> ");
+    }
+
+    // Code of a test case, everything between '--- Test' and '--- Expected'
+    fn case_source(name: &str) -> String {
+        let contents = read_to_string(format!("./src/test_cases/{}.tox", name)).unwrap();
+        contents
+            .lines()
+            .skip_while(|line| !line.starts_with("--- Test"))
+            .skip(1)
+            .take_while(|line| !line.starts_with("--- Expected"))
+            .collect::<Vec<&str>>()
+            .join("\n")
+    }
+
+    // Run the loop cases on both the tree-walker and the VM, the output has to match
+    #[test]
+    fn vm_matches_tree_walker() {
+        for name in ["while_math", "while", "while_else", "while_scopes"] {
+            let source = case_source(name);
+            let tree_walker = stdout(run_cli(&[], Some(&source), b""));
+            let vm = stdout(run_cli(&["--vm"], Some(&source), b""));
+            assert_eq!(tree_walker, vm, "{} differs on the VM", name);
+        }
+    }
+
+    // A loop the VM compiles fully, with a scope per iteration
+    // See the timings with `cargo test --release vm_benchmark -- --ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn vm_benchmark() {
+        let source = "var i = 0; var s = 0;
+            while (i < 300000) { var t = i * 2; s = s + t; i = i + 1; }
+            print s;";
+        let mut outputs = vec![];
+        for flags in [vec![], vec!["--vm"]] {
+            let start = std::time::Instant::now();
+            let output = run_cli(&flags, Some(source), b"");
+            println!("{:?}: {:?}", flags, start.elapsed());
+            outputs.push(stdout(output));
+        }
+        assert_eq!(outputs[0], outputs[1]);
+    }
+
+    // Print enough lines that a syscall per line would show, every line has to come out in order
+    #[test]
+    fn print_benchmark() {
+        let source = "var i = 0; while (i < 100000) print i = i + 1; printf(\"done\");";
+        let output = run_cli(&[], Some(source), b"");
+        let stdout = stdout(output);
+        let lines: Vec<&str> = stdout.lines().collect();
+        assert_eq!(lines.len(), 100001);
+        assert_eq!(lines[0], "1");
+        assert_eq!(lines[99999], "100000");
+        assert_eq!(lines[100000], "done");
+    }
+
     fn run_test(file: DirEntry) -> Result<(), String> {
         // Parse input and expected
         let contents = read_to_string(file.path()).unwrap();
@@ -48,31 +325,25 @@ mod tests {
             test_code.push(line.to_string());
         }
 
-        let idx = idx.expect(&format!(
-            "{:#?}: No expected section in test case definition",
-            file.file_name()
-        ));
+        let idx = idx.unwrap_or_else(|| {
+            panic!(
+                "{:#?}: No expected section in test case definition",
+                file.file_name()
+            )
+        });
 
         let mut expected_output = vec![];
 
         for line in &lines[idx + 1..] {
-            if line.len() > 0 {
+            if !line.is_empty() {
                 expected_output.push(*line);
             }
         }
 
         let input = test_code.join("\n");
 
-        let output = Command::new("cargo")
-            .arg("run")
-            .arg("e")
-            .arg(input)
-            .output()
-            .unwrap();
-        let lines = std::str::from_utf8(output.stdout.as_slice())
-            .unwrap()
-            .split("\n")
-            .collect::<Vec<&str>>();
+        let stdout = stdout(run_cli(&[], Some(&input), b""));
+        let lines = stdout.split("\n").collect::<Vec<&str>>();
 
         if !(lines.len() == expected_output.len() || lines.len() == expected_output.len() + 1) {
             return Err(format!(
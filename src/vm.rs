@@ -0,0 +1,464 @@
+use crate::environments::Environment;
+use crate::expr::{
+    apply_binary, apply_postfix, apply_unary, check_type, read_var, Expr, LiteralValue,
+};
+use crate::interpreter::Interpreter;
+use crate::output;
+use crate::scanner::{Token, TokenType};
+use crate::stmt::Stmt;
+use std::cell::RefCell;
+use std::error::Error;
+use std::rc::Rc;
+
+// A flat instruction for the stack machine
+// Jumps hold the index of the instruction to continue from
+#[derive(Clone, Debug)]
+pub enum Instr {
+    Constant(LiteralValue),
+    GetVar(String, Option<usize>),
+    // Assign the top of the stack and leave it there
+    SetVar(String, Option<usize>),
+    // Define a variable in the current scope with the top of the stack and pop it
+    DefineVar(String),
+    Unary(Token),
+    Postfix(Token),
+    Binary(Token),
+    Pop,
+    Print,
+    Jump(usize),
+    // Conditional jumps only peek so Logical can keep the value
+    JumpIfFalse(usize),
+    JumpIfTrue(usize),
+    PushScope,
+    PopScope,
+}
+
+// Lowers Stmt/Expr into Instrs
+// Anything it does not know about makes the whole compile return None so the tree-walker
+// can run it instead
+pub struct Compiler<'a> {
+    interpreter: &'a Interpreter,
+    code: Vec<Instr>,
+}
+
+impl<'a> Compiler<'a> {
+    pub fn new(interpreter: &'a Interpreter) -> Self {
+        Self {
+            interpreter,
+            code: vec![],
+        }
+    }
+
+    // Compile a single statement, None if it has unsupported parts
+    pub fn compile(mut self, stmt: &Stmt) -> Option<Vec<Instr>> {
+        self.stmt(stmt)?;
+        Some(self.code)
+    }
+
+    fn emit(&mut self, instr: Instr) -> usize {
+        self.code.push(instr);
+        self.code.len() - 1
+    }
+
+    // Point the jump at idx to the next instruction
+    fn patch(&mut self, idx: usize) {
+        let target = self.code.len();
+        match &mut self.code[idx] {
+            Instr::Jump(to) | Instr::JumpIfFalse(to) | Instr::JumpIfTrue(to) => *to = target,
+            _ => panic!("Tried to patch a non jump instruction"),
+        }
+    }
+
+    fn stmt(&mut self, stmt: &Stmt) -> Option<()> {
+        match stmt {
+            Stmt::Expression {
+                expression,
+                span: _,
+            } => {
+                self.expr(expression)?;
+                self.emit(Instr::Pop);
+            }
+            Stmt::Print {
+                expression,
+                span: _,
+            } => {
+                self.expr(expression)?;
+                self.emit(Instr::Print);
+            }
+            // Typed and lazy declarations are left to the tree-walker
+            Stmt::Var {
+                name: _,
+                initializer: _,
+                type_name: Some(_),
+                lazy: _,
+                span: _,
+            } => return None,
+            Stmt::Var {
+                name: _,
+                initializer: _,
+                type_name: None,
+                lazy: true,
+                span: _,
+            } => return None,
+            Stmt::Var {
+                name,
+                initializer,
+                type_name: None,
+                lazy: false,
+                span: _,
+            } => {
+                self.expr(initializer)?;
+                self.emit(Instr::DefineVar(name.lexeme.clone()));
+            }
+            Stmt::Block { stmts, span: _ } => {
+                self.emit(Instr::PushScope);
+                for stmt in stmts {
+                    self.stmt(stmt)?;
+                }
+                self.emit(Instr::PopScope);
+            }
+            Stmt::IfElse {
+                predicate,
+                then_branch,
+                else_branch,
+                span: _,
+            } => {
+                self.expr(predicate)?;
+                let to_else = self.emit(Instr::JumpIfFalse(0));
+                self.emit(Instr::Pop);
+                self.stmt(then_branch)?;
+                let to_end = self.emit(Instr::Jump(0));
+                self.patch(to_else);
+                self.emit(Instr::Pop);
+                if let Some(else_branch) = else_branch {
+                    self.stmt(else_branch)?;
+                }
+                self.patch(to_end);
+            }
+            // The first check decides between the body and the else_branch, later checks jump
+            // back to the top of the body
+            Stmt::WhileLoop {
+                cond,
+                body,
+                else_branch,
+                span: _,
+            } => {
+                self.expr(cond)?;
+                let to_else = self.emit(Instr::JumpIfFalse(0));
+                let top = self.emit(Instr::Pop);
+                self.stmt(body)?;
+                self.expr(cond)?;
+                self.emit(Instr::JumpIfTrue(top));
+                self.emit(Instr::Pop);
+                let to_end = self.emit(Instr::Jump(0));
+                self.patch(to_else);
+                self.emit(Instr::Pop);
+                if let Some(else_branch) = else_branch {
+                    self.stmt(else_branch)?;
+                }
+                self.patch(to_end);
+            }
+            Stmt::Function {
+                name: _,
+                params: _,
+                body: _,
+                pure: _,
+                span: _,
+            } => return None,
+            Stmt::Class {
+                name: _,
+                fields: _,
+                methods: _,
+                span: _,
+            } => return None,
+            Stmt::Return {
+                keyword: _,
+                value: _,
+                span: _,
+            } => return None,
+        }
+        Some(())
+    }
+
+    fn expr(&mut self, expr: &Expr) -> Option<()> {
+        match expr {
+            Expr::Literal { literal, span: _ } => {
+                self.emit(Instr::Constant(literal.clone()));
+            }
+            Expr::Grouping {
+                expression,
+                span: _,
+            } => self.expr(expression)?,
+            Expr::Variable { id, name, span: _ } => {
+                let distance = self.interpreter.get_distance(*id);
+                self.emit(Instr::GetVar(name.lexeme.clone(), distance));
+            }
+            Expr::Assign {
+                id,
+                name,
+                value,
+                span: _,
+            } => {
+                self.expr(value)?;
+                let distance = self.interpreter.get_distance(*id);
+                self.emit(Instr::SetVar(name.lexeme.clone(), distance));
+            }
+            Expr::Unary {
+                operator,
+                right,
+                span: _,
+            } => {
+                self.expr(right)?;
+                self.emit(Instr::Unary(operator.clone()));
+            }
+            Expr::Postfix {
+                operator,
+                operand,
+                span: _,
+            } => {
+                self.expr(operand)?;
+                self.emit(Instr::Postfix(operator.clone()));
+            }
+            Expr::Binary {
+                left,
+                operator,
+                right,
+                span: _,
+            } => {
+                self.expr(left)?;
+                self.expr(right)?;
+                self.emit(Instr::Binary(operator.clone()));
+            }
+            // Keep the LHS if it decides the result, otherwise drop it and use the RHS
+            Expr::Logical {
+                left,
+                operator,
+                right,
+                span: _,
+            } => {
+                self.expr(left)?;
+                let to_end = if operator.token_type == TokenType::Or {
+                    self.emit(Instr::JumpIfTrue(0))
+                } else {
+                    self.emit(Instr::JumpIfFalse(0))
+                };
+                self.emit(Instr::Pop);
+                self.expr(right)?;
+                self.patch(to_end);
+            }
+            // Laid out like an if/else that leaves the value of the branch it took
+            Expr::Ternary {
+                cond,
+                then_branch,
+                else_branch,
+                span: _,
+            } => {
+                self.expr(cond)?;
+                let to_else = self.emit(Instr::JumpIfFalse(0));
+                self.emit(Instr::Pop);
+                self.expr(then_branch)?;
+                let to_end = self.emit(Instr::Jump(0));
+                self.patch(to_else);
+                self.emit(Instr::Pop);
+                self.expr(else_branch)?;
+                self.patch(to_end);
+            }
+            Expr::Call {
+                callee: _,
+                paren: _,
+                args: _,
+                span: _,
+            } => return None,
+            Expr::AnonFunc {
+                paren: _,
+                args: _,
+                body: _,
+                span: _,
+            } => return None,
+            Expr::Block {
+                stmts: _,
+                value: _,
+                span: _,
+            } => return None,
+            Expr::Get {
+                object: _,
+                name: _,
+                span: _,
+            } => return None,
+            Expr::Set {
+                object: _,
+                name: _,
+                value: _,
+                span: _,
+            } => return None,
+            Expr::This {
+                id: _,
+                keyword: _,
+                span: _,
+            } => return None,
+        }
+        Some(())
+    }
+}
+
+// Run compiled code with env as the starting Environment
+// lenient_nil is passed on to apply_binary
+pub fn run(
+    code: &[Instr],
+    env: Rc<RefCell<Environment>>,
+    lenient_nil: bool,
+) -> Result<(), Box<dyn Error>> {
+    let mut env = env;
+    let mut stack: Vec<LiteralValue> = vec![];
+    // Popped scopes nothing else holds on to, reused so a loop body does not allocate one per
+    // iteration
+    let mut spare: Vec<Rc<RefCell<Environment>>> = vec![];
+    let mut ip = 0;
+    while ip < code.len() {
+        let instr = &code[ip];
+        ip += 1;
+        match instr {
+            Instr::Constant(value) => stack.push(value.clone()),
+            Instr::GetVar(name, distance) => stack.push(read_var(&env, name, *distance)?),
+            Instr::SetVar(name, distance) => {
+                let value = peek(&stack)?.clone();
+                let type_name = env.borrow().annotation(name, *distance);
+                if let Some(type_name) = type_name {
+                    check_type(name, &type_name, &value)?;
+                }
+                if !env.borrow_mut().assign(name, value, *distance) {
+                    return Err(format!("Variable {} has not been declared", name).into());
+                }
+            }
+            Instr::DefineVar(name) => {
+                let value = pop(&mut stack)?;
+                let scope = env.borrow().scope_distance();
+                env.borrow_mut().define(name.clone(), value, scope);
+            }
+            Instr::Unary(operator) => {
+                let right = pop(&mut stack)?;
+                stack.push(apply_unary(operator, &right)?);
+            }
+            Instr::Postfix(operator) => {
+                let operand = pop(&mut stack)?;
+                stack.push(apply_postfix(operator, &operand)?);
+            }
+            Instr::Binary(operator) => {
+                let right = pop(&mut stack)?;
+                let left = pop(&mut stack)?;
+                stack.push(apply_binary(&left, operator, &right, lenient_nil)?);
+            }
+            Instr::Pop => {
+                pop(&mut stack)?;
+            }
+            Instr::Print => {
+                output::write_line(&pop(&mut stack)?.display_string())?
+            }
+            Instr::Jump(to) => ip = *to,
+            Instr::JumpIfFalse(to) => {
+                if peek(&stack)?.is_falsy() == LiteralValue::Bool(true) {
+                    ip = *to;
+                }
+            }
+            Instr::JumpIfTrue(to) => {
+                if peek(&stack)?.is_truthy() == LiteralValue::Bool(true) {
+                    ip = *to;
+                }
+            }
+            Instr::PushScope => {
+                let scope = Environment::new_enclosed(env.clone());
+                // Only a spare with the same parent is reused, an Environment never changes its
+                // enclosing chain, see resolve_scope
+                let same_parent = |spare: &Rc<RefCell<Environment>>| {
+                    matches!(&spare.borrow().enclosing, Some(parent) if Rc::ptr_eq(parent, &env))
+                };
+                env = match spare.pop() {
+                    Some(reused) if same_parent(&reused) => {
+                        *reused.borrow_mut() = scope;
+                        reused
+                    }
+                    _ => Rc::new(RefCell::new(scope)),
+                };
+            }
+            Instr::PopScope => {
+                let enclosing = env.borrow().enclosing.clone();
+                match enclosing {
+                    Some(enclosing) => {
+                        let popped = std::mem::replace(&mut env, enclosing);
+                        if Rc::strong_count(&popped) == 1 {
+                            spare.push(popped);
+                        }
+                    }
+                    None => return Err("VM popped the outermost scope".into()),
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn pop(stack: &mut Vec<LiteralValue>) -> Result<LiteralValue, Box<dyn Error>> {
+    stack.pop().ok_or_else(|| "VM stack underflow".into())
+}
+
+fn peek(stack: &[LiteralValue]) -> Result<&LiteralValue, Box<dyn Error>> {
+    stack.last().ok_or_else(|| "VM stack underflow".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::scanner::Scanner;
+
+    fn parse(source: &str) -> Vec<Stmt> {
+        let tokens = Scanner::new(source).scan_tokens().unwrap();
+        Parser::new(tokens).parse().unwrap()
+    }
+
+    #[test]
+    fn runs_while_loop() {
+        let interpreter = Interpreter::new();
+        let stmts = parse("var i = 0; while (i < 10) i = i + 1; var a = 1; { var a = 2; }");
+        for stmt in &stmts {
+            let code = Compiler::new(&interpreter).compile(stmt).unwrap();
+            run(&code, interpreter.environments.clone(), false).unwrap();
+        }
+        let env = interpreter.environments.borrow();
+        assert_eq!(env.get("i", None), Some(LiteralValue::Number(10.0)));
+        assert_eq!(env.get("a", None), Some(LiteralValue::Number(1.0)));
+    }
+
+    #[test]
+    fn runs_while_else() {
+        let interpreter = Interpreter::new();
+        let stmts = parse("var a = 0; while (false) { a = 1; } else a = 2; var b = nil or 3;");
+        for stmt in &stmts {
+            let code = Compiler::new(&interpreter).compile(stmt).unwrap();
+            run(&code, interpreter.environments.clone(), false).unwrap();
+        }
+        let env = interpreter.environments.borrow();
+        assert_eq!(env.get("a", None), Some(LiteralValue::Number(2.0)));
+        assert_eq!(env.get("b", None), Some(LiteralValue::Number(3.0)));
+    }
+
+    #[test]
+    fn runs_ternary() {
+        let interpreter = Interpreter::new();
+        let stmts = parse("var a = 1 > 2 ? 1 : 2; var b = a == 2 ? nil ? 3 : 4 : 5;");
+        for stmt in &stmts {
+            let code = Compiler::new(&interpreter).compile(stmt).unwrap();
+            run(&code, interpreter.environments.clone(), false).unwrap();
+        }
+        let env = interpreter.environments.borrow();
+        assert_eq!(env.get("a", None), Some(LiteralValue::Number(2.0)));
+        assert_eq!(env.get("b", None), Some(LiteralValue::Number(4.0)));
+    }
+
+    #[test]
+    fn falls_back_on_calls() {
+        let interpreter = Interpreter::new();
+        let stmts = parse("while (true) print clock();");
+        assert!(Compiler::new(&interpreter).compile(&stmts[0]).is_none());
+    }
+}